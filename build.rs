@@ -39,6 +39,7 @@ fn main() {
 	let bindings = bindgen::builder()
 		.header(libbcachefs_dir.join("super-io.h").display().to_string())
 		.header(libbcachefs_dir.join("checksum.h").display().to_string())
+		.header(libbcachefs_dir.join("opts.h").display().to_string())
 		.header(top_dir
 			.join("libbcachefs")
 			.join("crypto.h")
@@ -48,6 +49,11 @@ fn main() {
 			.join("bcachefs_format.h")
 			.display()
 			.to_string())
+		.header(top_dir
+			.join("libbcachefs")
+			.join("libbcachefs.h")
+			.display()
+			.to_string())
 		.clang_arg(format!(
 			"-I{}",
 			top_dir.join("libbcachefs").join("include").display()
@@ -65,12 +71,20 @@ fn main() {
 		.whitelist_function("bch2_sb_field_.*")
 		.whitelist_function("bch2_chacha_encrypt_key")
 		.whitelist_function("derive_passphrase")
+		.whitelist_function("bch2_super_write")
+		.whitelist_function("bch2_opt_lookup")
+		.whitelist_function("bch2_opt_parse")
+		.whitelist_var("bch2_opt_table")
 		.whitelist_var("BCH_.*")
 		.whitelist_type("bch_kdf_types")
+		.whitelist_type("bch_data_type")
 		.whitelist_type("bch_sb_field_.*")
 		.whitelist_type("bch_encrypted_key")
 		.whitelist_type("nonce")
 		.rustified_enum("bch_kdf_types")
+		.rustified_enum("bch_data_type")
+		.rustified_enum("opt_type")
+		.bitfield_enum("opt_mode")
 		.opaque_type("gendisk")
 		.opaque_type("bkey")
 		.generate()
@@ -92,6 +106,9 @@ fn main() {
 		.whitelist_function("request_key")
 		.whitelist_function("add_key")
 		.whitelist_function("keyctl_search")
+		.whitelist_function("keyctl_get_persistent")
+		.whitelist_function("keyctl_revoke")
+		.whitelist_function("keyctl_unlink")
 		.whitelist_var("KEY_SPEC_.*")
 		.generate()
 		.unwrap();
@@ -0,0 +1,119 @@
+//! Ask for a password through systemd's password agents, which works in an initramfs with
+//! Plymouth, or at boot when there is no usable TTY. See
+//! https://systemd.io/PASSWORD_AGENTS/ for a description of the protocol.
+
+use crate::secret::{Passphrase, Secret};
+use crate::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const ASK_PASSWORD_DIR: &str = "/run/systemd/ask-password";
+
+/// How long we wait for an answer when no timeout is given, the same as systemd-ask-password
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Removes the question and the socket when we are done
+struct Question {
+	ask: PathBuf,
+	socket: PathBuf,
+}
+
+impl Drop for Question {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.ask);
+		let _ = std::fs::remove_file(&self.socket);
+	}
+}
+
+/// The time of CLOCK_MONOTONIC, which NotAfter is in
+fn monotonic_now() -> Duration {
+	let mut ts = libc::timespec {
+		tv_sec: 0,
+		tv_nsec: 0,
+	};
+	unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+	Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Ask the password agents for a password. `message` is shown to the user, and `id`
+/// identifies the requester, e.g. for Plymouth. Agents could be missing, so we give up after
+/// `timeout`.
+pub fn ask(message: &str, id: &str, timeout: Duration) -> crate::Result<Passphrase> {
+	use std::io::Write;
+	use std::os::unix::net::UnixDatagram;
+
+	let dir = Path::new(ASK_PASSWORD_DIR);
+	if !dir.is_dir() {
+		return Err(Error::Passphrase(format!(
+			"{} not found, is systemd running?",
+			ASK_PASSWORD_DIR
+		)));
+	}
+	let io_error = |path: &Path| {
+		let path = path.to_owned();
+		move |e| Error::Io { path, source: e }
+	};
+
+	let now = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap_or_default();
+	let suffix = format!("bcachefs-{}-{}", std::process::id(), now.as_nanos());
+	let question = Question {
+		ask: dir.join(format!("ask.{}", suffix)),
+		socket: dir.join(format!("sck.{}", suffix)),
+	};
+	let socket = UnixDatagram::bind(&question.socket).map_err(io_error(&question.socket))?;
+	socket
+		.set_read_timeout(Some(timeout))
+		.map_err(io_error(&question.socket))?;
+	// Agents drop the question after this, in microseconds of CLOCK_MONOTONIC
+	let not_after = (monotonic_now() + timeout).as_micros();
+
+	// Agents watch for new ask.* files, so write the question somewhere else first and
+	// rename it into place once it is complete
+	let tmp = dir.join(format!(".tmp.{}", suffix));
+	{
+		let mut f = std::fs::File::create(&tmp).map_err(io_error(&tmp))?;
+		write!(
+			f,
+			"[Ask]\nPID={}\nSocket={}\nAcceptCached=0\nEcho=0\nNotAfter={}\nMessage={}\nIcon=drive-harddisk\nId={}\n",
+			std::process::id(),
+			question.socket.display(),
+			not_after,
+			message,
+			id
+		)
+		.map_err(io_error(&tmp))?;
+	}
+	if let Err(e) = std::fs::rename(&tmp, &question.ask) {
+		let _ = std::fs::remove_file(&tmp);
+		return Err(io_error(&question.ask)(e));
+	}
+
+	// Only root can write to the socket, so we don't need to check the credentials of
+	// the sender
+	let mut buf = Secret::new([0u8; 4096]);
+	let len = match socket.recv(&mut *buf) {
+		Ok(len) => len,
+		Err(e)
+			if e.kind() == std::io::ErrorKind::WouldBlock
+				|| e.kind() == std::io::ErrorKind::TimedOut =>
+		{
+			return Err(Error::Passphrase(format!(
+				"No password agent answered in {} seconds",
+				timeout.as_secs()
+			)));
+		}
+		Err(e) => return Err(io_error(&question.socket)(e)),
+	};
+	let reply = &buf[..len];
+	match reply.first() {
+		Some(b'+') => Passphrase::from_bytes(&reply[1..]),
+		Some(b'-') => Err(Error::Passphrase(
+			"Password request is cancelled".to_owned(),
+		)),
+		_ => Err(Error::Passphrase(
+			"Invalid reply from password agent".to_owned(),
+		)),
+	}
+}
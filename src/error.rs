@@ -0,0 +1,164 @@
+use crate::filesystem::{MissingDevice, Replicas};
+use crate::spec::Spec;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Everything that can go wrong when finding, unlocking or mounting a filesystem
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+	/// None of the filesystems found matches the spec
+	#[error("Filesystem {0} is not found")]
+	NotFound(Spec),
+	/// Labels are not required to be unique, so a label could match more than one filesystem
+	#[error("More than one filesystem is labeled {0}")]
+	AmbiguousLabel(String),
+	#[error("{} is not a member of any bcachefs filesystem", .0.display())]
+	NotAMember(PathBuf),
+	/// A device given in the spec doesn't exist
+	#[error("Device {} is not found", .0.display())]
+	DeviceNotFound(PathBuf),
+	#[error("Filesystem {0} is not mounted")]
+	NotMounted(Uuid),
+	/// The filesystem is mounted, but it has to be unmounted for what is asked
+	#[error(
+		"Filesystem {uuid} is mounted on {}",
+		.mountpoints.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(" ")
+	)]
+	Mounted {
+		uuid: Uuid,
+		mountpoints: Vec<PathBuf>,
+	},
+	#[error("No bcachefs filesystem is mounted on {}", .0.display())]
+	NothingMounted(PathBuf),
+	#[error("Devices of different filesystems given: {0} and {1}")]
+	DifferentFilesystems(Uuid, Uuid),
+	#[error("Invalid filesystem specification {spec}: {reason}")]
+	InvalidSpec { spec: String, reason: String },
+
+	#[error("Filesystem {0} is not encrypted")]
+	NotEncrypted(Uuid),
+	/// The passphrase is still wrong after all the attempts
+	#[error("Failed to verify the passphrase of {0}")]
+	WrongPassphrase(Uuid),
+	/// The key is not in the keyring, and we are not allowed to ask for the passphrase
+	#[error("The key of {0} is not available")]
+	NoKey(Uuid),
+	#[error("Timed out waiting for the key of {0}")]
+	KeyTimeout(Uuid),
+	/// The passphrase can't be read, or it is not acceptable
+	#[error("{0}")]
+	Passphrase(String),
+	#[error("Invalid scrypt parameters: {0}")]
+	InvalidScrypt(String),
+	#[error("Encryption error: {0}")]
+	Crypto(&'static str),
+	/// Writing the new superblocks failed partway, `updated` are the devices that already have
+	/// the key encrypted with the new passphrase
+	#[error(
+		"Failed to write the superblock of {}: {source}, {}",
+		.path.display(),
+		updated_devices(.updated)
+	)]
+	SuperWrite {
+		path: PathBuf,
+		updated: Vec<PathBuf>,
+		source: std::io::Error,
+	},
+
+	#[error("Filesystem {uuid} is missing {}", join(.missing))]
+	MissingDevices {
+		uuid: Uuid,
+		missing: Vec<MissingDevice>,
+	},
+	/// Some data has no replica on the devices we found
+	#[error(
+		"Filesystem {uuid} is missing {}, which makes {} unavailable, mount in very degraded mode to mount it anyway",
+		join(.missing),
+		join(.unavailable)
+	)]
+	UnavailableData {
+		uuid: Uuid,
+		missing: Vec<MissingDevice>,
+		unavailable: Vec<Replicas>,
+	},
+
+	#[error("Permission denied: {}", .0.display())]
+	PermissionDenied(PathBuf),
+	/// A mount option is rejected before it gets to the kernel
+	#[error("Mount option {option} {reason}")]
+	InvalidOption { option: String, reason: String },
+	/// The kernel refused to mount, `messages` are the errors it gave through the new mount
+	/// API, if any
+	#[error(
+		"Failed to mount {uuid} on {}: {errno}{}",
+		.target.display(),
+		.messages.iter().map(|m| format!(", {}", m)).collect::<String>()
+	)]
+	Mount {
+		uuid: Uuid,
+		target: PathBuf,
+		errno: errno::Errno,
+		messages: Vec<String>,
+	},
+
+	/// A system call, or a C library function that sets errno, failed
+	#[error("{call} failed: {errno}")]
+	Sys {
+		call: &'static str,
+		errno: errno::Errno,
+	},
+	#[error("{}: {source}", .path.display())]
+	Io {
+		path: PathBuf,
+		source: std::io::Error,
+	},
+	#[error("Failed to enumerate block devices: {0}")]
+	Udev(#[source] std::io::Error),
+	#[error("Unexpected NUL byte in {0:?}")]
+	Nul(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+	/// Make an error of the failed system call from errno
+	pub(crate) fn sys(call: &'static str) -> Self {
+		Error::Sys {
+			call,
+			errno: errno::errno(),
+		}
+	}
+
+	/// The errno behind the error, if it comes from the kernel
+	pub fn errno(&self) -> Option<errno::Errno> {
+		match *self {
+			Error::Mount { errno, .. } | Error::Sys { errno, .. } => Some(errno),
+			Error::Io { ref source, .. } => source.raw_os_error().map(errno::Errno),
+			_ => None,
+		}
+	}
+}
+
+impl From<std::ffi::NulError> for Error {
+	fn from(e: std::ffi::NulError) -> Self {
+		Error::Nul(String::from_utf8_lossy(&e.into_vec()).into_owned())
+	}
+}
+
+fn join(items: &[impl std::fmt::Display]) -> String {
+	use itertools::Itertools;
+	items.iter().join(", ")
+}
+
+fn updated_devices(updated: &[PathBuf]) -> String {
+	use itertools::Itertools;
+	if updated.is_empty() {
+		"no device is updated".to_owned()
+	} else {
+		format!(
+			"{} already have the new passphrase",
+			updated.iter().map(|p| p.display()).join(", ")
+		)
+	}
+}
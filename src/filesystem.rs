@@ -9,85 +9,249 @@ pub struct FileSystem {
 	/// External UUID of the bcachefs
 	#[getset(get = "pub")]
 	uuid: uuid::Uuid,
+	/// Label of the filesystem, if it has one
+	#[getset(get = "pub")]
+	label: Option<String>,
 	/// Whether filesystem is encrypted
 	#[getset(get_copy = "pub")]
 	encrypted: bool,
 	/// Super block
-	#[getset(get = "pub")]
+	#[getset(get = "pub(crate)")]
 	sb: bcachefs::bch_sb_handle,
 	/// Member devices for this filesystem
 	#[getset(get = "pub")]
 	devices: Vec<PathBuf>,
+	/// Member index of each of the devices
+	dev_idx: Vec<u8>,
 }
 
-/// Parse a comma-separated mount options and split out mountflags and filesystem
-/// specific options.
-fn parse_mount_options(options: impl AsRef<str>) -> (Option<String>, u64) {
-	use either::Either::*;
-	let (opts, flags) = options
-		.as_ref()
-		.split(",")
-		.map(|o| match o {
-			"dirsync" => Left(libc::MS_DIRSYNC),
-			"lazytime" => Left(1 << 25), // MS_LAZYTIME
-			"mand" => Left(libc::MS_MANDLOCK),
-			"noatime" => Left(libc::MS_NOATIME),
-			"nodev" => Left(libc::MS_NODEV),
-			"nodiratime" => Left(libc::MS_NODIRATIME),
-			"noexec" => Left(libc::MS_NOEXEC),
-			"nosuid" => Left(libc::MS_NOSUID),
-			"ro" => Left(libc::MS_RDONLY),
-			"rw" => Left(0),
-			"relatime" => Left(libc::MS_RELATIME),
-			"strictatime" => Left(libc::MS_STRICTATIME),
-			"sync" => Left(libc::MS_SYNCHRONOUS),
-			"" => Left(0),
-			o @ _ => Right(o),
-		})
-		.fold((Vec::new(), 0), |(mut opts, flags), next| match next {
-			Left(f) => (opts, flags | f),
-			Right(o) => {
-				opts.push(o);
-				(opts, flags)
-			}
-		});
+/// A member device recorded in the superblock, but not found on the system
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct MissingDevice {
+	/// Index of the device in the filesystem
+	pub index: u8,
+	/// UUID of the member device
+	pub uuid: Uuid,
+}
 
-	use itertools::Itertools;
-	(
-		if opts.len() == 0 {
-			None
-		} else {
-			Some(opts.iter().join(","))
-		},
-		flags,
-	)
+impl std::fmt::Display for MissingDevice {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "member {} ({})", self.index, self.uuid)
+	}
+}
+
+/// A replicas entry from the superblock
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Replicas {
+	/// Value of enum bch_data_type
+	pub data_type: u8,
+	/// Number of devices needed to read the data
+	pub nr_required: u8,
+	/// Indices of the devices the data is on
+	pub devs: Vec<u8>,
+}
+
+impl Replicas {
+	/// Name of the data type, as in enum bch_data_type
+	pub fn data_type_name(&self) -> &'static str {
+		match self.data_type {
+			1 => "superblock",
+			2 => "journal",
+			3 => "btree",
+			4 => "user",
+			5 => "cached",
+			_ => "unknown",
+		}
+	}
+}
+
+impl std::fmt::Display for Replicas {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		use itertools::Itertools;
+		write!(
+			f,
+			"{} data on devices {} ({} required)",
+			self.data_type_name(),
+			self.devs.iter().join(","),
+			self.nr_required
+		)
+	}
+}
+
+/// How far we are willing to go when some member devices are missing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Degraded {
+	/// Refuse to mount
+	No,
+	/// Mount as long as all data is still available
+	Degraded,
+	/// Mount even if some data is unavailable
+	VeryDegraded,
 }
 
 impl FileSystem {
 	pub(crate) fn new(sb: bcachefs::bch_sb_handle) -> Self {
+		let label = sb.sb().label();
 		Self {
 			uuid: sb.sb().uuid(),
+			label: if label.is_empty() { None } else { Some(label) },
 			encrypted: sb.sb().crypt().is_some(),
 			sb: sb,
 			devices: vec![],
+			dev_idx: vec![],
 		}
 	}
 
+	fn add_device(&mut self, path: PathBuf, dev_idx: u8) {
+		if !self.devices.contains(&path) {
+			self.devices.push(path);
+			self.dev_idx.push(dev_idx);
+		}
+	}
+
+	/// Data that can't be read without the missing devices. Cached data is not included
+	/// since it is not needed.
+	pub fn unavailable_data(&self) -> Vec<Replicas> {
+		self.sb
+			.sb()
+			.replicas()
+			.into_iter()
+			.filter(|r| r.data_type != crate::bcachefs::bch_data_type::BCH_DATA_cached as u8)
+			.filter(|r| {
+				let present = r.devs.iter().filter(|d| self.dev_idx.contains(d)).count();
+				present < r.nr_required as usize
+			})
+			.collect()
+	}
+
+	/// Check whether this filesystem can be mounted with the devices we found, and return
+	/// the extra mount option needed to mount it
+	pub fn degraded_option(&self, degraded: Degraded) -> crate::Result<Option<&'static str>> {
+		use crate::Error;
+		use itertools::Itertools;
+		use log::warn;
+
+		let missing = self.missing_devices();
+		if missing.is_empty() {
+			return Ok(None);
+		}
+		let unavailable = self.unavailable_data();
+		match degraded {
+			Degraded::No => {
+				warn!("Mount in degraded mode to mount {} anyway", self.uuid);
+				Err(Error::MissingDevices {
+					uuid: self.uuid,
+					missing,
+				})
+			}
+			Degraded::Degraded if !unavailable.is_empty() => Err(Error::UnavailableData {
+				uuid: self.uuid,
+				missing,
+				unavailable,
+			}),
+			Degraded::Degraded => {
+				warn!(
+					"Mounting {} without {}",
+					self.uuid,
+					missing.iter().join(", ")
+				);
+				Ok(Some("degraded"))
+			}
+			Degraded::VeryDegraded => {
+				warn!(
+					"Mounting {} without {}",
+					self.uuid,
+					missing.iter().join(", ")
+				);
+				if !unavailable.is_empty() {
+					warn!("Unavailable data: {}", unavailable.iter().join(", "));
+				}
+				Ok(Some("very_degraded"))
+			}
+		}
+	}
+
+	/// Member devices listed in the superblock that we didn't find
+	pub fn missing_devices(&self) -> Vec<MissingDevice> {
+		self.sb
+			.sb()
+			.members()
+			.iter()
+			.enumerate()
+			.filter(|(i, m)| m.exists() && !self.dev_idx.contains(&(*i as u8)))
+			.map(|(i, m)| MissingDevice {
+				index: i as u8,
+				uuid: m.uuid(),
+			})
+			.collect()
+	}
+
 	pub fn mount(
 		&self,
 		target: impl AsRef<std::path::Path>,
-		options: impl AsRef<str>,
-	) -> anyhow::Result<()> {
+		options: &MountOptions,
+	) -> crate::Result<()> {
+		use itertools::Itertools;
+		use log::info;
+
+		// Try the new mount API first, which gives better error messages
+		let src = self.devices.iter().map(|d| d.display()).join(":");
+		match crate::mount_api::mount(
+			"bcachefs",
+			&src,
+			target.as_ref(),
+			options.data().as_deref(),
+			options.flags(),
+		) {
+			Ok(()) => Ok(()),
+			Err(e) if crate::mount_api::is_unsupported(&e) => {
+				info!("The new mount API is not supported, falling back to mount(2)");
+				self.mount_with_flags(target, options, 0)
+			}
+			Err(e) => Err(self.mount_error(target.as_ref(), e)),
+		}
+	}
+
+	/// Change the options of the filesystem mounted on `target`. Like mount(8), the flags it is
+	/// mounted with are kept, unless `options` changes them.
+	pub fn remount(
+		&self,
+		target: impl AsRef<std::path::Path>,
+		options: &MountOptions,
+	) -> crate::Result<()> {
+		use log::debug;
+
+		let target = target.as_ref();
+		// The last one is on top, if there is more than one mount on target
+		let current = crate::mountinfo::mounts()?
+			.into_iter()
+			.rev()
+			.find(|m| m.mountpoint == target)
+			.map_or_else(MountOptions::new, |m| {
+				MountOptions::from_mountinfo(&m.options, &m.super_options)
+			});
+		let options = options.merged_with(&current);
+		debug!("Remounting {} with \"{}\"", target.display(), options);
+		self.mount_with_flags(target, &options, libc::MS_REMOUNT)
+	}
+
+	fn mount_with_flags(
+		&self,
+		target: impl AsRef<std::path::Path>,
+		options: &MountOptions,
+		flags: libc::c_ulong,
+	) -> crate::Result<()> {
 		use itertools::Itertools;
 		use std::ffi::c_void;
 		use std::os::raw::c_char;
 		use std::os::unix::ffi::OsStrExt;
 		let src = self.devices.iter().map(|d| d.display()).join(":");
-		let (data, mountflags) = parse_mount_options(options);
+		let (data, mountflags) = (options.data(), options.flags());
 		let fstype = c_str!("bcachefs");
+		let path = target.as_ref();
 
 		let src = std::ffi::CString::new(src)?; // bind the CString to keep it alive
-		let target = std::ffi::CString::new(target.as_ref().as_os_str().as_bytes())?; // ditto
+		let target = std::ffi::CString::new(path.as_os_str().as_bytes())?; // ditto
 		let data = data.map(|data| std::ffi::CString::new(data)).transpose()?; // ditto
 
 		let src = src.as_c_str().to_bytes_with_nul().as_ptr() as *const c_char;
@@ -96,79 +260,235 @@ impl FileSystem {
 			data.as_c_str().to_bytes_with_nul().as_ptr() as *const c_void
 		});
 
-		let ret = unsafe { libc::mount(src, target, fstype, mountflags, data) };
+		let ret = unsafe { libc::mount(src, target, fstype, mountflags | flags, data) };
 		if ret == 0 {
 			Ok(())
 		} else {
-			Err(crate::ErrnoError(errno::errno()).into())
+			Err(self.mount_error(path, errno::errno().into()))
 		}
 	}
+
+	/// Tell a mount refused for lack of privileges from one the kernel refused because of the
+	/// filesystem or its options
+	fn mount_error(
+		&self,
+		target: &std::path::Path,
+		failure: crate::mount_api::Failure,
+	) -> crate::Error {
+		match failure.errno.0 {
+			libc::EPERM | libc::EACCES => crate::Error::PermissionDenied(target.to_owned()),
+			_ => crate::Error::Mount {
+				uuid: self.uuid,
+				target: target.to_owned(),
+				errno: failure.errno,
+				messages: failure.messages,
+			},
+		}
+	}
+
+	/// Where this filesystem is currently mounted
+	pub fn mountpoints(&self) -> crate::Result<Vec<PathBuf>> {
+		let devices: Vec<_> = self
+			.devices
+			.iter()
+			.filter_map(|d| device_number(d))
+			.collect();
+		Ok(crate::mountinfo::mounts()?
+			.into_iter()
+			.filter(|m| m.fstype == "bcachefs")
+			.filter(|m| self.is_source(&m.source, &devices))
+			.map(|m| m.mountpoint)
+			.collect())
+	}
+
+	/// Whether the source of a mount is this filesystem. The kernel shows the source as it
+	/// was given to mount(2), which could be the member devices, or the UUID.
+	fn is_source(&self, source: &str, devices: &[libc::dev_t]) -> bool {
+		use crate::spec::Spec;
+		match source.parse() {
+			Ok(Spec::Uuid(uuid)) => uuid == self.uuid,
+			Ok(Spec::Devices(paths)) => paths
+				.iter()
+				.any(|p| device_number(p).map_or(false, |d| devices.contains(&d))),
+			_ => false,
+		}
+	}
+}
+
+/// Get the device number of a block device, so different paths to the same device compare
+/// equal
+fn device_number(path: &std::path::Path) -> Option<libc::dev_t> {
+	use std::os::unix::fs::{FileTypeExt, MetadataExt};
+	let metadata = std::fs::metadata(path).ok()?;
+	if metadata.file_type().is_block_device() {
+		Some(metadata.rdev())
+	} else {
+		None
+	}
+}
+
+/// Bind mount `source` on `target`
+pub fn bind_mount(source: &std::path::Path, target: &std::path::Path) -> crate::Result<()> {
+	use std::os::unix::ffi::OsStrExt;
+	let source = std::ffi::CString::new(source.as_os_str().as_bytes())?;
+	let target = std::ffi::CString::new(target.as_os_str().as_bytes())?;
+	let ret = unsafe {
+		libc::mount(
+			source.as_ptr(),
+			target.as_ptr(),
+			std::ptr::null(),
+			libc::MS_BIND,
+			std::ptr::null(),
+		)
+	};
+	if ret == 0 {
+		Ok(())
+	} else {
+		Err(crate::Error::sys("mount"))
+	}
 }
 
 use crate::bcachefs;
+use crate::mount_options::MountOptions;
 use std::collections::HashMap;
 use uuid::Uuid;
-pub fn probe_filesystems() -> anyhow::Result<HashMap<Uuid, FileSystem>> {
+
+/// Find the filesystems with the given label, there could be more than one since labels
+/// are not required to be unique
+pub fn find_by_label<'a>(fss: &'a HashMap<Uuid, FileSystem>, label: &str) -> Vec<&'a FileSystem> {
+	fss.values()
+		.filter(|fs| fs.label().as_ref().map_or(false, |l| l == label))
+		.collect()
+}
+
+/// Unmount whatever is mounted on `target`, `flags` are the flags of umount2(2)
+pub fn umount(target: &std::path::Path, flags: libc::c_int) -> crate::Result<()> {
+	use std::os::unix::ffi::OsStrExt;
+	let target = std::ffi::CString::new(target.as_os_str().as_bytes())?;
+	let ret = unsafe { libc::umount2(target.as_ptr(), flags) };
+	if ret == 0 {
+		Ok(())
+	} else {
+		Err(crate::Error::sys("umount2"))
+	}
+}
+
+/// Read the superblock of a block device
+pub(crate) fn read_super(path: &std::path::Path) -> crate::Result<bcachefs::bch_sb_handle> {
 	use std::os::unix::ffi::OsStrExt;
-	let mut udev = udev::Enumerator::new()?;
+	let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+	unsafe {
+		let mut opts = std::mem::MaybeUninit::<bcachefs::bch_opts>::zeroed();
+		let mut sb = std::mem::MaybeUninit::zeroed();
+		// Don't open the device exclusively, so we can see filesystems
+		// that are already mounted
+		(*opts.as_mut_ptr()).set_noexcl_defined(1);
+		(*opts.as_mut_ptr()).noexcl = 1;
+		let ret = bcachefs::bch2_read_super(c_path.as_ptr(), opts.as_mut_ptr(), sb.as_mut_ptr());
+		if ret == -libc::EACCES {
+			Err(crate::Error::PermissionDenied(path.to_owned()))
+		} else if ret != 0 {
+			Err(crate::Error::Sys {
+				call: "bch2_read_super",
+				errno: errno::Errno(-ret),
+			})
+		} else {
+			Ok(sb.assume_init())
+		}
+	}
+}
+
+/// Add the block device to the filesystem it belongs to, if it is a bcachefs member device
+fn probe_device(fss: &mut HashMap<Uuid, FileSystem>, path: &std::path::Path) -> crate::Result<()> {
+	match read_super(path) {
+		Ok(sb) => {
+			let dev_idx = sb.sb().dev_idx;
+			fss.entry(sb.sb().uuid())
+				.or_insert_with(|| FileSystem::new(sb))
+				.add_device(path.to_owned(), dev_idx);
+			Ok(())
+		}
+		Err(e @ crate::Error::PermissionDenied(_)) => Err(e),
+		Err(_) => Ok(()),
+	}
+}
+
+/// Run `f` while stopping libbcachefs from spamming the output
+fn gagged<T>(f: impl FnOnce() -> T) -> T {
+	let _gag = gag::Gag::stdout().unwrap();
+	let ret = f();
+	// Flush stdout so buffered output don't get printed after we remove the gag
+	unsafe {
+		libc::fflush(stdout);
+	}
+	ret
+}
+
+pub fn probe_filesystems() -> crate::Result<HashMap<Uuid, FileSystem>> {
+	use crate::Error::Udev;
+	let mut udev = udev::Enumerator::new().map_err(Udev)?;
 	let mut fss = HashMap::new();
-	udev.match_subsystem("block")?;
+	udev.match_subsystem("block").map_err(Udev)?;
 
-	{
-		// Stop libbcachefs from spamming the output
-		let _gag = gag::Gag::stdout().unwrap();
-		for dev in udev.scan_devices()? {
+	gagged(|| -> crate::Result<()> {
+		for dev in udev.scan_devices().map_err(Udev)? {
 			if let Some(p) = dev.devnode() {
-				let path =
-					std::ffi::CString::new(p.as_os_str().as_bytes()).unwrap();
-				let result = unsafe {
-					let mut opts = std::mem::MaybeUninit::zeroed();
-					let mut sb = std::mem::MaybeUninit::zeroed();
-					let ret = bcachefs::bch2_read_super(
-						path.as_ptr(),
-						opts.as_mut_ptr(),
-						sb.as_mut_ptr(),
-					);
-					if ret == -libc::EACCES {
-						Err(std::io::Error::new(
-							std::io::ErrorKind::PermissionDenied,
-							"no permission",
-						))
-					} else if ret != 0 {
-						Err(std::io::Error::new(
-							std::io::ErrorKind::Other,
-							"failed to read super",
-						))
-					} else {
-						Ok((opts.assume_init(), sb.assume_init()))
-					}
-				};
-				match result {
-					Ok((_, sb)) => match fss.get_mut(&sb.sb().uuid()) {
-						None => {
-							let mut fs = FileSystem::new(sb);
-							fs.devices.push(p.to_owned());
-							fss.insert(fs.uuid, fs);
-						}
-						Some(fs) => {
-							fs.devices.push(p.to_owned());
-						}
-					},
-					Err(e) if e.kind()
-						!= std::io::ErrorKind::PermissionDenied =>
-					{
-						()
-					}
-					e @ Err(_) => {
-						e?;
-					}
-				}
+				probe_device(&mut fss, p)?;
 			}
 		}
-		// Flush stdout so buffered output don't get printed after we remove the gag
-		unsafe {
-			libc::fflush(stdout);
+		Ok(())
+	})?;
+	Ok(fss)
+}
+
+/// Like `probe_filesystems`, but keep watching for new block devices until `done` returns
+/// true, or `timeout` has passed.
+pub fn probe_filesystems_until(
+	timeout: std::time::Duration,
+	done: impl Fn(&HashMap<Uuid, FileSystem>) -> bool,
+) -> crate::Result<HashMap<Uuid, FileSystem>> {
+	use crate::Error::Udev;
+	use log::{info, warn};
+	use std::os::unix::io::AsRawFd;
+
+	// Start listening before scanning, so we won't miss devices appearing in between
+	let mut monitor = udev::MonitorBuilder::new()
+		.and_then(|m| m.match_subsystem("block"))
+		.and_then(|m| m.listen())
+		.map_err(Udev)?;
+	let mut fss = probe_filesystems()?;
+	let deadline = std::time::Instant::now() + timeout;
+	while !done(&fss) {
+		let now = std::time::Instant::now();
+		if now >= deadline {
+			warn!("Timed out waiting for devices");
+			break;
+		}
+
+		let mut pollfd = libc::pollfd {
+			fd: monitor.as_raw_fd(),
+			events: libc::POLLIN,
+			revents: 0,
+		};
+		let timeout = (deadline - now).as_millis().min(libc::c_int::max_value() as u128);
+		let ret = unsafe { libc::poll(&mut pollfd, 1, timeout as libc::c_int) };
+		if ret < 0 && errno::errno().0 != libc::EINTR {
+			return Err(crate::Error::sys("poll"));
 		}
+
+		gagged(|| -> crate::Result<()> {
+			for event in monitor.by_ref() {
+				match event.event_type() {
+					udev::EventType::Add | udev::EventType::Change => (),
+					_ => continue,
+				}
+				if let Some(p) = event.devnode() {
+					info!("Block device {} appeared", p.display());
+					probe_device(&mut fss, p)?;
+				}
+			}
+			Ok(())
+		})?;
 	}
 	Ok(fss)
 }
@@ -1,96 +1,588 @@
-use log::info;
+use crate::keyutils::key_serial_t;
+use crate::Error;
+use log::{info, warn};
 
-fn check_for_key(key_name: &std::ffi::CStr) -> anyhow::Result<bool> {
-	use crate::keyutils::{self, keyctl_search};
+/// Which keyring the key is searched for, and added to
+#[derive(parse_display::FromStr, parse_display::Display, Debug, Clone, Copy)]
+#[display(style = "snake_case")]
+pub enum Keyring {
+	User,
+	Session,
+	Persistent,
+	#[display("{0}")]
+	Serial(key_serial_t),
+}
+
+impl Keyring {
+	/// Get the serial number of the keyring, special or not
+	fn serial(self) -> crate::Result<key_serial_t> {
+		use crate::keyutils::{self, keyctl_get_persistent};
+		match self {
+			Keyring::User => Ok(keyutils::KEY_SPEC_USER_KEYRING),
+			Keyring::Session => Ok(keyutils::KEY_SPEC_SESSION_KEYRING),
+			Keyring::Persistent => {
+				// Link the persistent keyring of the current user to our process keyring
+				let ret = unsafe { keyctl_get_persistent(!0, keyutils::KEY_SPEC_PROCESS_KEYRING) };
+				if ret < 0 {
+					Err(Error::sys("keyctl_get_persistent"))
+				} else {
+					Ok(ret as key_serial_t)
+				}
+			}
+			Keyring::Serial(serial) => Ok(serial),
+		}
+	}
+}
+
+/// Find the key with the given name in the keyring
+fn find_key(
+	key_name: &std::ffi::CStr,
+	keyring: key_serial_t,
+) -> crate::Result<Option<key_serial_t>> {
+	use crate::keyutils::keyctl_search;
 	let key_name = key_name.to_bytes_with_nul().as_ptr() as *const _;
 	let key_type = c_str!("logon");
 
-	let key_id =
-		unsafe { keyctl_search(keyutils::KEY_SPEC_USER_KEYRING, key_type, key_name, 0) };
+	let key_id = unsafe { keyctl_search(keyring, key_type, key_name, 0) };
 	if key_id > 0 {
+		Ok(Some(key_id as key_serial_t))
+	} else if errno::errno().0 != libc::ENOKEY {
+		Err(Error::sys("keyctl_search"))
+	} else {
+		Ok(None)
+	}
+}
+
+fn check_for_key(key_name: &std::ffi::CStr, keyring: key_serial_t) -> crate::Result<bool> {
+	if find_key(key_name, keyring)?.is_some() {
 		info!("Key has became avaiable");
 		Ok(true)
-	} else if errno::errno().0 != libc::ENOKEY {
-		Err(crate::ErrnoError(errno::errno()).into())
 	} else {
 		Ok(false)
 	}
 }
 
-fn wait_for_key(uuid: &uuid::Uuid) -> anyhow::Result<()> {
+/// A notification pipe receiving changes to a keyring, see Documentation/watch_queue.rst
+/// in the kernel source
+struct KeyringWatch {
+	fds: [libc::c_int; 2],
+}
+
+impl KeyringWatch {
+	const O_NOTIFICATION_PIPE: libc::c_int = libc::O_EXCL;
+	const IOC_WATCH_QUEUE_SET_SIZE: libc::c_ulong = 0x5760; // _IO('W', 0x60)
+	const KEYCTL_WATCH_KEY: libc::c_long = 32;
+
+	fn new(keyring: key_serial_t) -> crate::Result<Self> {
+		let mut fds = [-1; 2];
+		let flags = Self::O_NOTIFICATION_PIPE | libc::O_CLOEXEC;
+		if unsafe { libc::pipe2(fds.as_mut_ptr(), flags) } < 0 {
+			return Err(Error::sys("pipe2"));
+		}
+		let watch = Self { fds };
+		if unsafe { libc::ioctl(fds[0], Self::IOC_WATCH_QUEUE_SET_SIZE, 16) } < 0 {
+			return Err(Error::sys("ioctl"));
+		}
+		let ret = unsafe {
+			libc::syscall(
+				libc::SYS_keyctl,
+				Self::KEYCTL_WATCH_KEY,
+				keyring,
+				fds[0],
+				0x01,
+			)
+		};
+		if ret < 0 {
+			return Err(Error::sys("keyctl"));
+		}
+		Ok(watch)
+	}
+
+	/// Wait for the keyring to change, returns false if timed out
+	fn wait(&self, timeout: Option<std::time::Duration>) -> crate::Result<bool> {
+		let mut pollfd = libc::pollfd {
+			fd: self.fds[0],
+			events: libc::POLLIN,
+			revents: 0,
+		};
+		let timeout = timeout.map_or(-1, |t| {
+			t.as_millis().min(libc::c_int::max_value() as u128) as libc::c_int
+		});
+		let ret = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+		if ret < 0 {
+			return if errno::errno().0 == libc::EINTR {
+				Ok(true)
+			} else {
+				Err(Error::sys("poll"))
+			};
+		}
+		if ret == 0 {
+			return Ok(false);
+		}
+
+		// We don't care about what the notifications are, just drain them
+		let mut buf = [0u8; 512];
+		unsafe { libc::read(self.fds[0], buf.as_mut_ptr() as *mut _, buf.len()) };
+		Ok(true)
+	}
+}
+
+impl Drop for KeyringWatch {
+	fn drop(&mut self) {
+		for fd in self.fds.iter().filter(|&&fd| fd >= 0) {
+			unsafe { libc::close(*fd) };
+		}
+	}
+}
+
+fn wait_for_key(
+	uuid: &uuid::Uuid,
+	keyring: key_serial_t,
+	timeout: Option<std::time::Duration>,
+) -> crate::Result<()> {
+	use std::time::{Duration, Instant};
+
 	let key_name = std::ffi::CString::new(format!("bcachefs:{}", uuid)).unwrap();
+	let deadline = timeout.map(|t| Instant::now() + t);
+	// Start watching before checking for the key, so we won't miss it being added in between.
+	// Fall back to polling when the kernel doesn't support keyring notifications.
+	// The watch only sees changes to the keyring itself, not to the keyrings linked into it that
+	// keyctl_search also looks in, so check again at least every second.
+	let poll = Duration::from_secs(1);
+	let watch = KeyringWatch::new(keyring)
+		.map_err(|e| {
+			info!(
+				"Cannot watch the keyring, polling for the key instead: {}",
+				e
+			)
+		})
+		.ok();
 	loop {
-		if check_for_key(&key_name)? {
+		if check_for_key(&key_name, keyring)? {
 			break Ok(());
 		}
 
-		std::thread::sleep(std::time::Duration::from_secs(1));
+		let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+		if remaining == Some(Duration::from_secs(0)) {
+			break Err(Error::KeyTimeout(*uuid));
+		}
+		let wait = remaining.map_or(poll, |r| r.min(poll));
+		match &watch {
+			Some(watch) => {
+				watch.wait(Some(wait))?;
+			}
+			None => std::thread::sleep(wait),
+		}
 	}
 }
 
 const BCH_KEY_MAGIC: &str = "bch**key";
 use crate::filesystem::FileSystem;
-fn ask_for_key(fs: &FileSystem) -> anyhow::Result<()> {
-	use crate::bcachefs::{self, bch2_chacha_encrypt_key, bch_encrypted_key, bch_key};
-	use anyhow::anyhow;
+use crate::secret::{Passphrase, Secret};
+
+/// What a correct passphrase gives access to
+struct DerivedKey {
+	/// The key derived from the passphrase, which is what goes into the keyring
+	passphrase_key: Secret<crate::bcachefs::bch_key>,
+	/// The decrypted key from the superblock
+	key: Secret<crate::bcachefs::bch_encrypted_key>,
+}
+
+/// Derive the key from the passphrase, returns None if it can't decrypt the key stored
+/// in the superblock
+fn derive_key(fs: &FileSystem, pass: &mut Passphrase) -> crate::Result<Option<DerivedKey>> {
+	use crate::bcachefs::{self, bch2_chacha_encrypt_key, bch_encrypted_key};
 	use byteorder::{LittleEndian, ReadBytesExt};
+
+	let bch_key_magic = BCH_KEY_MAGIC.as_bytes().read_u64::<LittleEndian>().unwrap();
+	let crypt = fs.sb().sb().crypt().unwrap();
+	pass.trim_end();
+	let mut output = Secret::new(Default::default());
+	*output = unsafe { bcachefs::derive_passphrase(crypt as *const _ as *mut _, pass.as_ptr()) };
+
+	let mut key = Secret::new(*crypt.key());
+	let ret = unsafe {
+		bch2_chacha_encrypt_key(
+			&mut *output as *mut _,
+			fs.sb().sb().nonce(),
+			&mut *key as *mut _ as *mut _,
+			std::mem::size_of::<bch_encrypted_key>() as u64,
+		)
+	};
+	if ret != 0 {
+		Err(Error::Crypto("chacha decryption failure"))
+	} else if key.magic != bch_key_magic {
+		Ok(None)
+	} else {
+		Ok(Some(DerivedKey {
+			passphrase_key: output,
+			key,
+		}))
+	}
+}
+
+/// Read the passphrase until it is correct, or we run out of attempts
+fn verify_passphrase(
+	fs: &FileSystem,
+	attempts: u32,
+	mut read_passphrase: impl FnMut() -> crate::Result<Passphrase>,
+) -> crate::Result<DerivedKey> {
+	let mut attempts_left = attempts;
+	loop {
+		let mut pass = read_passphrase()?;
+		match derive_key(fs, &mut pass)? {
+			Some(key) => break Ok(key),
+			None if attempts_left > 1 => {
+				attempts_left -= 1;
+				warn!("Wrong passphrase, {} attempts left", attempts_left);
+			}
+			None => break Err(Error::WrongPassphrase(*fs.uuid())),
+		}
+	}
+}
+
+fn ask_for_key(
+	fs: &FileSystem,
+	keyring: key_serial_t,
+	attempts: u32,
+	read_passphrase: impl FnMut() -> crate::Result<Passphrase>,
+) -> crate::Result<()> {
+	use crate::bcachefs::bch_key;
 	use std::os::raw::c_char;
 
 	let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
-	if check_for_key(&key_name)? {
+	if check_for_key(&key_name, keyring)? {
 		return Ok(());
 	}
 
-	let bch_key_magic = BCH_KEY_MAGIC.as_bytes().read_u64::<LittleEndian>().unwrap();
-	let crypt = fs.sb().sb().crypt().unwrap();
-	let pass = rpassword::read_password_from_tty(Some("Enter passphrase: "))?;
-	let pass = std::ffi::CString::new(pass.trim_end())?; // bind to keep the CString alive
-	let mut output: bch_key = unsafe {
-		bcachefs::derive_passphrase(
-			crypt as *const _ as *mut _,
-			pass.as_c_str().to_bytes_with_nul().as_ptr() as *const _,
+	let output = verify_passphrase(fs, attempts, read_passphrase)?.passphrase_key;
+
+	let key_type = c_str!("logon");
+	let ret = unsafe {
+		crate::keyutils::add_key(
+			key_type,
+			key_name.as_c_str().to_bytes_with_nul() as *const _ as *const c_char,
+			&*output as *const _ as *const _,
+			std::mem::size_of::<bch_key>() as u64,
+			keyring,
 		)
 	};
+	if ret == -1 {
+		Err(Error::sys("add_key"))
+	} else {
+		info!("Added key {} to keyring {}", ret, keyring);
+		Ok(())
+	}
+}
+
+/// Make sure the key of the filesystem is in the keyring, returns the serial number of the
+/// keyring used
+pub fn prepare_key(
+	fs: &FileSystem,
+	password: crate::PasswordInput,
+	keyring: Keyring,
+	timeout: Option<std::time::Duration>,
+	attempts: u32,
+) -> crate::Result<key_serial_t> {
+	use crate::PasswordInput::*;
+
+	let serial = keyring.serial()?;
+	info!("Using the {} keyring ({})", keyring, serial);
+	match password {
+		Fail => Err(Error::NoKey(*fs.uuid())),
+		Wait => wait_for_key(fs.uuid(), serial, timeout),
+		Ask => ask_for_key(fs, serial, attempts, || {
+			Passphrase::read_from_tty("Enter passphrase: ")
+		}),
+		Stdin => ask_for_key(fs, serial, 1, || Passphrase::read_line(libc::STDIN_FILENO)),
+		File(path) => ask_for_key(fs, serial, 1, || {
+			use std::os::unix::io::AsRawFd;
+			let f = std::fs::File::open(&path).map_err(|e| Error::Io {
+				path: path.clone().into(),
+				source: e,
+			})?;
+			Passphrase::read_all(f.as_raw_fd())
+		}),
+		Systemd => ask_for_key(fs, serial, attempts, || {
+			let name = fs.label().clone().unwrap_or_else(|| fs.uuid().to_string());
+			crate::ask_password::ask(
+				&format!("Please enter passphrase for bcachefs filesystem {}:", name),
+				&format!("bcachefs:{}", fs.uuid()),
+				timeout.unwrap_or(crate::ask_password::DEFAULT_TIMEOUT),
+			)
+		}),
+	}
+	.map(|_| serial)
+}
+
+/// New scrypt parameters for the passphrase, the ones not given are kept
+#[derive(Debug, Default)]
+pub struct ScryptParams {
+	pub n: Option<u64>,
+	pub r: Option<u64>,
+	pub p: Option<u64>,
+}
+
+impl ScryptParams {
+	fn apply(&self, crypt: &mut crate::bcachefs::bch_sb_field_crypt) -> crate::Result<()> {
+		let mut flags = crypt
+			.scrypt_flags()
+			.ok_or_else(|| Error::InvalidScrypt("the key is not derived with scrypt".to_owned()))?;
+		// All three are stored as their log2
+		let log2 = |name, v: u64, min| {
+			if v < min || !v.is_power_of_two() {
+				Err(Error::InvalidScrypt(format!(
+					"{} must be a power of two, got {}",
+					name, v
+				)))
+			} else {
+				Ok(v.trailing_zeros() as u64)
+			}
+		};
+		if let Some(n) = self.n {
+			flags.set_N(log2("N", n, 2)?);
+		}
+		if let Some(r) = self.r {
+			flags.set_R(log2("r", r, 1)?);
+		}
+		if let Some(p) = self.p {
+			flags.set_P(log2("p", p, 1)?);
+		}
+		crypt.set_scrypt_flags(flags);
+		Ok(())
+	}
+}
+
+/// Verify the current passphrase, and encrypt the key of the filesystem with a new one. The
+/// superblocks of all the member devices are rewritten, so the filesystem must not be mounted.
+pub fn change_passphrase(
+	fs: &FileSystem,
+	attempts: u32,
+	scrypt: &ScryptParams,
+) -> crate::Result<()> {
+	use crate::bcachefs::{self, bch2_chacha_encrypt_key, bch_encrypted_key};
+	use std::os::unix::io::AsRawFd;
 
-	let mut key = crypt.key().clone();
+	let old = verify_passphrase(fs, attempts, || {
+		Passphrase::read_from_tty("Enter current passphrase: ")
+	})?;
+
+	let mut new = Passphrase::read_from_tty("Enter new passphrase: ")?;
+	let mut again = Passphrase::read_from_tty("Enter the same passphrase again: ")?;
+	new.trim_end();
+	again.trim_end();
+	if new.as_bytes() != again.as_bytes() {
+		return Err(Error::Passphrase("Passphrases do not match".to_owned()));
+	}
+	if new.as_bytes().is_empty() {
+		return Err(Error::Passphrase("Passphrase is empty".to_owned()));
+	}
+
+	// Every member has its own superblock, which differ in the device index at least
+	let mut sbs = fs
+		.devices()
+		.iter()
+		.map(|d| Ok((d, crate::filesystem::read_super(d)?)))
+		.collect::<crate::Result<Vec<_>>>()?;
+	let seq = sbs.iter().map(|(_, sb)| sb.sb().seq).max().unwrap_or(0) + 1;
+	for (_, sb) in sbs.iter_mut() {
+		let crypt = sb
+			.sb_mut()
+			.crypt_mut()
+			.ok_or_else(|| Error::NotEncrypted(*fs.uuid()))?;
+		scrypt.apply(crypt)?;
+	}
+
+	let mut new_key = Secret::new(Default::default());
+	let crypt = sbs[0].1.sb_mut().crypt_mut().unwrap();
+	*new_key = unsafe { bcachefs::derive_passphrase(crypt, new.as_ptr()) };
+	let mut key = old.key;
 	let ret = unsafe {
 		bch2_chacha_encrypt_key(
-			&mut output as *mut _,
+			&mut *new_key as *mut _,
 			fs.sb().sb().nonce(),
-			&mut key as *mut _ as *mut _,
+			&mut *key as *mut _ as *mut _,
 			std::mem::size_of::<bch_encrypted_key>() as u64,
 		)
 	};
 	if ret != 0 {
-		Err(anyhow!("chache decryption failure"))
-	} else if key.magic != bch_key_magic {
-		Err(anyhow!("failed to verify the password"))
-	} else {
-		let key_type = c_str!("logon");
-		let ret = unsafe {
-			crate::keyutils::add_key(
-				key_type,
-				key_name.as_c_str().to_bytes_with_nul() as *const _
-					as *const c_char,
-				&output as *const _ as *const _,
-				std::mem::size_of::<bch_key>() as u64,
-				crate::keyutils::KEY_SPEC_USER_KEYRING,
-			)
-		};
-		if ret == -1 {
-			Err(anyhow!("failed to add key to keyring: {}", errno::errno()))
-		} else {
-			Ok(())
+		return Err(Error::Crypto("chacha encryption failure"));
+	}
+
+	// Open all the devices first, so we find the ones we can't write to before any of them
+	// is changed
+	let devs = sbs
+		.iter()
+		.map(|(path, _)| {
+			std::fs::OpenOptions::new()
+				.write(true)
+				.open(path)
+				.map_err(|e| Error::Io {
+					path: path.to_path_buf(),
+					source: e,
+				})
+		})
+		.collect::<crate::Result<Vec<_>>>()?;
+	let mut updated = vec![];
+	for ((path, sb), dev) in sbs.iter_mut().zip(&devs) {
+		let sb = sb.sb_mut();
+		sb.seq = seq;
+		sb.crypt_mut().unwrap().key = *key;
+		// bch2_super_write() has no return value, it exits the process if a write fails. The
+		// writes can still fail when they are flushed, so sync before moving on.
+		unsafe { bcachefs::bch2_super_write(dev.as_raw_fd(), sb) };
+		dev.sync_all().map_err(|e| Error::SuperWrite {
+			path: path.to_path_buf(),
+			updated: updated.clone(),
+			source: e,
+		})?;
+		updated.push(path.to_path_buf());
+		info!("Updated the superblock on {}", path.display());
+	}
+	Ok(())
+}
+
+/// Whether the key of the filesystem is in the keyring
+pub fn has_key(uuid: &uuid::Uuid, keyring: Keyring) -> crate::Result<bool> {
+	let key_name = std::ffi::CString::new(format!("bcachefs:{}", uuid)).unwrap();
+	Ok(find_key(&key_name, keyring.serial()?)?.is_some())
+}
+
+/// The function used to derive the key from the passphrase, with its parameters
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Kdf {
+	Scrypt { n: u64, r: u64, p: u64 },
+	Unknown { id: u64 },
+}
+
+impl Kdf {
+	/// Returns None if the filesystem is not encrypted
+	pub fn of(fs: &FileSystem) -> Option<Self> {
+		Some(Self::of_crypt(fs.sb().sb().crypt()?))
+	}
+
+	fn of_crypt(crypt: &crate::bcachefs::bch_sb_field_crypt) -> Self {
+		match crypt.scrypt_flags() {
+			// N, r and p are all stored as their log2
+			Some(flags) => Kdf::Scrypt {
+				n: 1u64.checked_shl(flags.N() as u32).unwrap_or(0),
+				r: 1u64.checked_shl(flags.R() as u32).unwrap_or(0),
+				p: 1u64.checked_shl(flags.P() as u32).unwrap_or(0),
+			},
+			None => Kdf::Unknown {
+				id: crypt.kdf_type(),
+			},
+		}
+	}
+
+	/// Bytes of memory needed to derive the key
+	pub fn memory(&self) -> Option<u64> {
+		match *self {
+			Kdf::Scrypt { n, r, .. } => Some(128u64.saturating_mul(r).saturating_mul(n)),
+			Kdf::Unknown { .. } => None,
+		}
+	}
+
+	/// How many times the Salsa20/8 core is run to derive the key, which is proportional to
+	/// the time it takes
+	pub fn rounds(&self) -> Option<u64> {
+		match *self {
+			Kdf::Scrypt { n, r, p } => {
+				Some(4u64.saturating_mul(n).saturating_mul(r).saturating_mul(p))
+			}
+			Kdf::Unknown { .. } => None,
 		}
 	}
 }
 
-pub(crate) fn prepare_key(fs: &FileSystem, password: crate::PasswordInput) -> anyhow::Result<()> {
-	use crate::PasswordInput::*;
-	use anyhow::anyhow;
-	match password {
-		Fail => Err(anyhow!("no key available")),
-		Wait => Ok(wait_for_key(fs.uuid())?),
-		Ask => ask_for_key(fs),
+impl std::fmt::Display for Kdf {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Kdf::Scrypt { n, r, p } => write!(f, "scrypt (N={}, r={}, p={})", n, r, p),
+			Kdf::Unknown { id } => write!(f, "unknown ({})", id),
+		}
+	}
+}
+
+/// Revoke the key of the filesystem and unlink it from the keyring, so the key material
+/// doesn't linger after the filesystem is unmounted. Returns false if there is no key.
+pub fn revoke_key(uuid: &uuid::Uuid, keyring: Keyring) -> crate::Result<bool> {
+	use crate::keyutils::{keyctl_revoke, keyctl_unlink};
+
+	let serial = keyring.serial()?;
+	let key_name = std::ffi::CString::new(format!("bcachefs:{}", uuid)).unwrap();
+	let key = match find_key(&key_name, serial)? {
+		Some(key) => key,
+		None => return Ok(false),
+	};
+	if unsafe { keyctl_revoke(key) } < 0 {
+		return Err(Error::sys("keyctl_revoke"));
+	}
+	// The key could have been found in a keyring nested in this one, in which case it is
+	// not linked here. It is revoked anyway.
+	if unsafe { keyctl_unlink(key, serial) } < 0 && errno::errno().0 != libc::ENOENT {
+		return Err(Error::sys("keyctl_unlink"));
+	}
+	info!(
+		"Revoked key {} in the {} keyring ({})",
+		key, keyring, serial
+	);
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn scrypt_params_round_trip() {
+		let mut crypt: crate::bcachefs::bch_sb_field_crypt = unsafe { std::mem::zeroed() };
+		let params = ScryptParams {
+			n: Some(1 << 14),
+			r: Some(8),
+			p: Some(16),
+		};
+		params.apply(&mut crypt).unwrap();
+		match Kdf::of_crypt(&crypt) {
+			Kdf::Scrypt { n, r, p } => assert_eq!((n, r, p), (1 << 14, 8, 16)),
+			kdf => panic!("unexpected kdf {}", kdf),
+		}
+
+		// The ones not given are kept
+		ScryptParams {
+			r: Some(1),
+			..Default::default()
+		}
+		.apply(&mut crypt)
+		.unwrap();
+		match Kdf::of_crypt(&crypt) {
+			Kdf::Scrypt { n, r, p } => assert_eq!((n, r, p), (1 << 14, 1, 16)),
+			kdf => panic!("unexpected kdf {}", kdf),
+		}
+	}
+
+	#[test]
+	fn scrypt_params_invalid() {
+		let mut crypt: crate::bcachefs::bch_sb_field_crypt = unsafe { std::mem::zeroed() };
+		for params in &[
+			ScryptParams {
+				n: Some(1),
+				..Default::default()
+			},
+			ScryptParams {
+				r: Some(0),
+				..Default::default()
+			},
+			ScryptParams {
+				p: Some(12),
+				..Default::default()
+			},
+		] {
+			match params.apply(&mut crypt) {
+				Err(Error::InvalidScrypt(_)) => {}
+				ret => panic!("{:?} gave {:?}", params, ret),
+			}
+		}
 	}
 }
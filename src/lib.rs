@@ -0,0 +1,239 @@
+//! Find bcachefs filesystems by their UUID, label or member devices, load the keys of
+//! encrypted ones into the kernel keyring, and mount them.
+
+macro_rules! c_str {
+	($lit:expr) => {
+		unsafe { std::ffi::CStr::from_ptr(concat!($lit, "\0").as_ptr() as *const std::os::raw::c_char)
+			       .to_bytes_with_nul()
+			       .as_ptr() as *const std::os::raw::c_char }
+	};
+}
+
+/// Where the passphrase of an encrypted filesystem comes from
+#[derive(parse_display::FromStr, parse_display::Display, Debug, Clone)]
+#[display(style = "snake_case")]
+pub enum PasswordInput {
+	Fail,
+	Wait,
+	Ask,
+	Stdin,
+	#[display("file:{0}")]
+	File(String),
+	Systemd,
+}
+
+mod ask_password;
+mod error;
+pub mod filesystem;
+pub mod key;
+mod mount_api;
+pub mod mount_options;
+mod mountinfo;
+mod secret;
+pub mod spec;
+
+pub use error::{Error, Result};
+mod keyutils {
+	#![allow(non_upper_case_globals)]
+	#![allow(non_camel_case_types)]
+	#![allow(non_snake_case)]
+	#![allow(unused)]
+
+	include!(concat!(env!("OUT_DIR"), "/keyutils.rs"));
+}
+
+mod bcachefs {
+	#![allow(non_upper_case_globals)]
+	#![allow(non_camel_case_types)]
+	#![allow(non_snake_case)]
+	#![allow(unused)]
+
+	include!(concat!(env!("OUT_DIR"), "/bcachefs.rs"));
+
+	use bitfield::bitfield;
+	bitfield! {
+		pub struct bch_scrypt_flags(u64);
+		pub N, set_N: 15, 0;
+		pub R, set_R: 31, 16;
+		pub P, set_P: 47, 32;
+	}
+	bitfield! {
+		pub struct bch_crypt_flags(u64);
+		TYPE, _: 4, 0;
+	}
+	use memoffset::offset_of;
+	impl bch_sb_field_crypt {
+		/// The value of enum bch_kdf_types
+		pub fn kdf_type(&self) -> u64 {
+			bch_crypt_flags(self.flags).TYPE()
+		}
+		pub fn scrypt_flags(&self) -> Option<bch_scrypt_flags> {
+			if self.kdf_type() != bch_kdf_types::BCH_KDF_SCRYPT as u64 {
+				None
+			} else {
+				Some(bch_scrypt_flags(self.kdf_flags))
+			}
+		}
+		pub fn set_scrypt_flags(&mut self, flags: bch_scrypt_flags) {
+			self.kdf_flags = flags.0;
+		}
+		pub fn key(&self) -> &bch_encrypted_key {
+			&self.key
+		}
+	}
+	impl bch_sb {
+		fn crypt_ptr(&self) -> *mut bch_sb_field_crypt {
+			unsafe {
+				let ptr = bch2_sb_field_get(
+					self as *const _ as *mut _,
+					bch_sb_field_type::BCH_SB_FIELD_crypt,
+				) as *mut u8;
+				if ptr.is_null() {
+					std::ptr::null_mut()
+				} else {
+					let offset = offset_of!(bch_sb_field_crypt, field);
+					ptr.sub(offset) as *mut _
+				}
+			}
+		}
+		pub fn crypt(&self) -> Option<&bch_sb_field_crypt> {
+			unsafe { self.crypt_ptr().as_ref() }
+		}
+		pub fn crypt_mut(&mut self) -> Option<&mut bch_sb_field_crypt> {
+			unsafe { self.crypt_ptr().as_mut() }
+		}
+		pub fn uuid(&self) -> uuid::Uuid {
+			uuid::Uuid::from_bytes(self.user_uuid.b)
+		}
+
+		/// Get the replicas entries, which record what data is replicated on which devices
+		pub fn replicas(&self) -> Vec<crate::filesystem::Replicas> {
+			// The entries are variable length, and in the old v0 format they don't
+			// have the nr_required field
+			let (ptr, header) = unsafe {
+				let ptr = bch2_sb_field_get(
+					self as *const _ as *mut _,
+					bch_sb_field_type::BCH_SB_FIELD_replicas,
+				);
+				if ptr.is_null() {
+					let ptr = bch2_sb_field_get(
+						self as *const _ as *mut _,
+						bch_sb_field_type::BCH_SB_FIELD_replicas_v0,
+					);
+					(ptr, 2)
+				} else {
+					(ptr, 3)
+				}
+			};
+			if ptr.is_null() {
+				return vec![];
+			}
+
+			let mut ret = vec![];
+			unsafe {
+				let end = (ptr as *const u8).add((*ptr).u64s as usize * 8);
+				let mut entry = (ptr as *const u8).add(std::mem::size_of::<bch_sb_field>());
+				while entry.add(header) <= end && *entry != 0 {
+					let nr_devs = *entry.add(1) as usize;
+					if entry.add(header + nr_devs) > end {
+						break;
+					}
+					ret.push(crate::filesystem::Replicas {
+						data_type: *entry,
+						nr_required: if header == 2 { 1 } else { *entry.add(2) },
+						devs: std::slice::from_raw_parts(entry.add(header), nr_devs).to_vec(),
+					});
+					entry = entry.add(header + nr_devs);
+				}
+			}
+			ret
+		}
+
+		/// Get the member device slots, indexed by device index
+		pub fn members(&self) -> &[bch_member] {
+			unsafe {
+				let ptr = bch2_sb_field_get(
+					self as *const _ as *mut _,
+					bch_sb_field_type::BCH_SB_FIELD_members,
+				) as *const u8;
+				if ptr.is_null() {
+					&[]
+				} else {
+					let offset = offset_of!(bch_sb_field_members, field);
+					let members = &*(ptr.sub(offset) as *const bch_sb_field_members);
+					members.members.as_slice(self.nr_devices as usize)
+				}
+			}
+		}
+
+		/// Get the filesystem label, which is NUL padded in the superblock
+		pub fn label(&self) -> String {
+			let len = self.label.iter().position(|&c| c == 0).unwrap_or(self.label.len());
+			String::from_utf8_lossy(&self.label[..len]).into_owned()
+		}
+
+		/// Get the nonce used to encrypt the superblock
+		pub fn nonce(&self) -> nonce {
+			use byteorder::{ReadBytesExt, LittleEndian};
+			let mut internal_uuid = &self.uuid.b[..];
+			let dword1 = internal_uuid.read_u32::<LittleEndian>().unwrap();
+			let dword2 = internal_uuid.read_u32::<LittleEndian>().unwrap();
+			nonce { d: [0, 0, dword1, dword2] }
+		}
+	}
+	impl bch_option {
+		/// Look up a filesystem option by its name
+		pub fn lookup(name: &str) -> Option<&'static bch_option> {
+			let name = std::ffi::CString::new(name).ok()?;
+			let id = unsafe { bch2_opt_lookup(name.as_ptr()) };
+			if id < 0 {
+				None
+			} else {
+				Some(unsafe { &*bch2_opt_table.as_ptr().add(id as usize) })
+			}
+		}
+		/// Parse a value for the option, returns None if it is not valid
+		pub fn parse(&self, value: &str) -> Option<u64> {
+			let value = std::ffi::CString::new(value).ok()?;
+			let mut ret = 0;
+			let err = unsafe { bch2_opt_parse(std::ptr::null_mut(), self, value.as_ptr(), &mut ret) };
+			if err < 0 {
+				None
+			} else {
+				Some(ret)
+			}
+		}
+		/// The possible values of a string option
+		pub fn choices(&self) -> Vec<String> {
+			if self.type_ != opt_type::BCH_OPT_STR {
+				return vec![];
+			}
+			let mut ret = vec![];
+			unsafe {
+				let mut choice = self.__bindgen_anon_1.__bindgen_anon_2.choices;
+				while !(*choice).is_null() {
+					ret.push(std::ffi::CStr::from_ptr(*choice).to_string_lossy().into_owned());
+					choice = choice.add(1);
+				}
+			}
+			ret
+		}
+	}
+	impl bch_member {
+		/// Whether there is a device in this slot
+		pub fn exists(&self) -> bool {
+			self.uuid.b.iter().any(|&b| b != 0)
+		}
+		pub fn uuid(&self) -> uuid::Uuid {
+			uuid::Uuid::from_bytes(self.uuid.b)
+		}
+	}
+	impl bch_sb_handle {
+		pub fn sb(&self) -> &bch_sb {
+			unsafe { &*self.sb }
+		}
+		pub fn sb_mut(&mut self) -> &mut bch_sb {
+			unsafe { &mut *self.sb }
+		}
+	}
+}
@@ -1,169 +1,812 @@
+use anyhow::Context;
+use bcachefs_mount::{filesystem, key, mount_options, spec, Error, PasswordInput};
 use structopt::StructOpt;
-use anyhow::anyhow;
-
-#[macro_export]
-macro_rules! c_str {
-	($lit:expr) => {
-		unsafe { std::ffi::CStr::from_ptr(concat!($lit, "\0").as_ptr() as *const std::os::raw::c_char)
-			       .to_bytes_with_nul()
-			       .as_ptr() as *const std::os::raw::c_char }
-	};
-}
 
-#[derive(Debug)]
-struct ErrnoError(errno::Errno);
-impl std::fmt::Display for ErrnoError {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-		self.0.fmt(f)
-	}
-}
-impl std::error::Error for ErrnoError {}
+#[derive(StructOpt, Debug)]
+enum Command {
+	/// List the bcachefs filesystems that can be found
+	List {
+		/// Print the list in JSON
+		#[structopt(long)]
+		json: bool,
+	},
+
+	/// Load the key of an encrypted filesystem into the keyring, without mounting it
+	Unlock {
+		/// Where the password would be loaded from, see the help of the mount options
+		#[structopt(short, long, default_value = "ask")]
+		password: PasswordInput,
+
+		/// Which keyring to add the key to
+		#[structopt(long, default_value = "user")]
+		keyring: key::Keyring,
+
+		/// How many times to ask for the password before giving up
+		#[structopt(long, default_value = "3")]
+		attempts: u32,
+
+		/// The bcachefs filesystem to unlock, by UUID, label or member devices
+		spec: spec::Spec,
+	},
+
+	/// Revoke the key of an encrypted filesystem, and remove it from the keyring
+	Lock {
+		/// Which keyring to remove the key from
+		#[structopt(long, default_value = "user")]
+		keyring: key::Keyring,
+
+		/// The bcachefs filesystem to lock, by UUID, label or member devices
+		spec: spec::Spec,
+	},
+
+	/// Show the encryption details of a filesystem, and whether its key is loaded
+	Info {
+		/// Which keyring to look for the key in
+		#[structopt(long, default_value = "user")]
+		keyring: key::Keyring,
+
+		/// Print the details in JSON
+		#[structopt(long)]
+		json: bool,
+
+		/// The bcachefs filesystem to show, by UUID, label or member devices
+		spec: spec::Spec,
+	},
+
+	/// Change the passphrase of an encrypted filesystem, which must not be mounted
+	Passwd {
+		/// How many times to ask for the current passphrase before giving up
+		#[structopt(long, default_value = "3")]
+		attempts: u32,
+
+		/// New scrypt N parameter, the CPU and memory cost, which must be a power of two
+		#[structopt(long, value_name = "N")]
+		scrypt_n: Option<u64>,
+
+		/// New scrypt r parameter, the block size, which must be a power of two
+		#[structopt(long, value_name = "r")]
+		scrypt_r: Option<u64>,
+
+		/// New scrypt p parameter, the parallelization, which must be a power of two
+		#[structopt(long, value_name = "p")]
+		scrypt_p: Option<u64>,
+
+		/// Which keyring to revoke the key derived from the old passphrase in
+		#[structopt(long, default_value = "user")]
+		keyring: key::Keyring,
+
+		/// The bcachefs filesystem to change, by UUID, label or member devices
+		spec: spec::Spec,
+	},
+
+	/// Unmount a filesystem from all the places it is mounted on
+	Umount {
+		/// Detach the filesystem now, and clean up when it is no longer busy
+		#[structopt(short, long)]
+		lazy: bool,
+
+		/// Force the unmount, even if the filesystem is busy
+		#[structopt(short, long)]
+		force: bool,
+
+		/// Revoke the key of the filesystem after unmounting it
+		#[structopt(long)]
+		lock: bool,
+
+		/// Which keyring to remove the key from, with "--lock"
+		#[structopt(long, default_value = "user")]
+		keyring: key::Keyring,
 
-#[derive(parse_display::FromStr, parse_display::Display, Debug)]
-#[display(style = "snake_case")]
-pub(crate) enum PasswordInput {
-	Fail,
-	Wait,
-	Ask,
+		/// The bcachefs filesystem to unmount, by UUID, label or member devices, or one of its
+		/// mountpoints
+		target: String,
+	},
+
+	/// Change the mount options of a mounted filesystem
+	Remount {
+		/// The new mount options
+		#[structopt(short, default_value = "")]
+		options: mount_options::MountOptions,
+
+		/// The bcachefs filesystem to remount, by UUID, label or member devices, or one of its
+		/// mountpoints
+		target: String,
+	},
 }
 
 #[derive(StructOpt, Debug)]
-/// Mount a bcachefs filesystem by its UUID.
+#[structopt(setting = structopt::clap::AppSettings::ArgsNegateSubcommands)]
+/// Mount a bcachefs filesystem by its UUID, label or member devices.
 struct Options {
+	#[structopt(subcommand)]
+	command: Option<Command>,
+
 	/// Where the password would be loaded from.
 	///
 	/// Possible values are:
 	/// "fail" - don't ask for password, fail if filesystem is encrypted;
 	/// "wait" - wait for password to become available before mounting;
 	/// "ask" -  prompt the user for password;
+	/// "stdin" - read the password from the first line of stdin;
+	/// "file:<path>" - read the password from a file;
+	/// "systemd" - ask for the password through systemd's password agents;
 	#[structopt(short, long, default_value = "fail")]
 	password: PasswordInput,
 
-	/// External UUID of the bcachefs filesystem
-	uuid: uuid::Uuid,
+	/// Which keyring to look for the key in, and to add the key to.
+	///
+	/// Possible values are: "user", "session", "persistent", or the serial number of a keyring
+	#[structopt(long, default_value = "user")]
+	keyring: key::Keyring,
+
+	/// How many times to ask for the password before giving up, when the password is asked
+	/// for interactively
+	#[structopt(long, default_value = "3")]
+	attempts: u32,
+
+	/// The bcachefs filesystem to mount.
+	///
+	/// Either its external UUID (optionally prefixed with "UUID="), "LABEL=<label>",
+	/// or one or more of its member devices joined by ":".
+	spec: Option<spec::Spec>,
 
 	/// Where the filesystem should be mounted
 	mountpoint: Option<std::path::PathBuf>,
 
 	/// Mount options
 	#[structopt(short, default_value = "")]
-	options: String,
+	options: mount_options::MountOptions,
+
+	/// Allow mounting with missing devices, as long as all data is still available
+	#[structopt(long)]
+	degraded: bool,
+
+	/// Allow mounting with missing devices, even if some data is unavailable
+	#[structopt(long, conflicts_with = "degraded")]
+	very_degraded: bool,
+
+	/// Wait up to this many seconds for missing member devices to appear
+	#[structopt(long, value_name = "timeout")]
+	wait_devices: Option<u64>,
+
+	/// Give up waiting for the key after this many seconds, when using "--password wait" or
+	/// "--password systemd", which waits 90 seconds by default
+	#[structopt(long, value_name = "timeout")]
+	key_timeout: Option<u64>,
+
+	/// If the filesystem is already mounted elsewhere, bind mount it on the mountpoint,
+	/// instead of failing
+	#[structopt(long)]
+	bind: bool,
+
+	/// Drop unknown mount options with a warning, instead of failing
+	#[structopt(long)]
+	sloppy: bool,
 }
 
-mod filesystem;
-mod key;
-mod keyutils {
-	#![allow(non_upper_case_globals)]
-	#![allow(non_camel_case_types)]
-	#![allow(non_snake_case)]
-	#![allow(unused)]
+impl Options {
+	fn degraded(&self) -> filesystem::Degraded {
+		if self.very_degraded {
+			filesystem::Degraded::VeryDegraded
+		} else if self.degraded {
+			filesystem::Degraded::Degraded
+		} else {
+			filesystem::Degraded::No
+		}
+	}
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "mount.bcachefs")]
+/// Mount helper for mount(8), invoked as mount.bcachefs.
+struct HelperOptions {
+	/// Tolerate sloppy mount options rather than failing
+	#[structopt(short)]
+	sloppy: bool,
+
+	/// Do everything except the actual mount system call
+	#[structopt(short)]
+	fake: bool,
+
+	/// Mount without writing in /etc/mtab
+	#[structopt(short = "n")]
+	#[allow(dead_code)] // we never write /etc/mtab anyway
+	no_mtab: bool,
+
+	/// Print informational messages
+	#[structopt(short)]
+	verbose: bool,
 
-	include!(concat!(env!("OUT_DIR"), "/keyutils.rs"));
+	/// Filesystem type, passed by mount(8) for subtypes
+	#[structopt(short = "t")]
+	#[allow(dead_code)] // accepted for mount(8) compatibility
+	fstype: Option<String>,
+
+	/// The bcachefs filesystem to mount, by UUID, label or member devices
+	spec: spec::Spec,
+
+	/// Where the filesystem should be mounted
+	mountpoint: std::path::PathBuf,
+
+	/// Mount options, including the ones for mount(8) itself
+	#[structopt(short, default_value = "")]
+	options: String,
 }
 
-mod bcachefs {
-	#![allow(non_upper_case_globals)]
-	#![allow(non_camel_case_types)]
-	#![allow(non_snake_case)]
-	#![allow(unused)]
+/// Whether the option is one that mount(8) handles itself, but still passes to helpers
+fn is_userspace_option(option: &str) -> bool {
+	const USERSPACE: &[&str] = &[
+		"auto",
+		"noauto",
+		"nofail",
+		"_netdev",
+		"user",
+		"nouser",
+		"users",
+		"owner",
+		"group",
+		"comment",
+		"loop",
+		"offset",
+		"sizelimit",
+		"encryption",
+		"helper",
+	];
+	let name = option.split('=').next().unwrap_or("");
+	USERSPACE.contains(&name) || name.starts_with("x-") || name.starts_with("X-")
+}
 
-	include!(concat!(env!("OUT_DIR"), "/bcachefs.rs"));
+fn mount(spec: &spec::Spec, opt: &Options, fake: bool) -> anyhow::Result<()> {
+	use itertools::Itertools;
+	use log::info;
+	use std::time::Duration;
 
-	use bitfield::bitfield;
-	bitfield! {
-		pub struct bch_scrypt_flags(u64);
-		pub N, _: 15, 0;
-		pub R, _: 31, 16;
-		pub P, _: 47, 32;
-	}
-	bitfield! {
-		pub struct bch_crypt_flags(u64);
-		TYPE, _: 4, 0;
-	}
-	use memoffset::offset_of;
-	impl bch_sb_field_crypt {
-		pub fn scrypt_flags(&self) -> Option<bch_scrypt_flags> {
-			let t = bch_crypt_flags(self.flags);
-			if t.TYPE() != bch_kdf_types::BCH_KDF_SCRYPT as u64 {
-				None
-			} else {
-				Some(bch_scrypt_flags(self.kdf_flags))
-			}
-		}
-		pub fn key(&self) -> &bch_encrypted_key {
-			&self.key
+	let fss = match opt.wait_devices {
+		Some(timeout) => {
+			filesystem::probe_filesystems_until(Duration::from_secs(timeout), |fss| {
+				spec.resolve(fss)
+					.map_or(false, |fs| fs.missing_devices().is_empty())
+			})?
 		}
+		None => filesystem::probe_filesystems()?,
+	};
+	info!("Found {} bcachefs filesystems: ", fss.len());
+	for fs in fss.values() {
+		info!(
+			"{} [{}] ({}): {}",
+			fs.uuid(),
+			fs.label().as_deref().unwrap_or(""),
+			if fs.encrypted() {
+				"encrypted"
+			} else {
+				"unencrypted"
+			},
+			fs.devices().iter().map(|d| d.display()).join(" ")
+		);
 	}
-	impl bch_sb {
-		pub fn crypt(&self) -> Option<&bch_sb_field_crypt> {
-			unsafe {
-				let ptr = bch2_sb_field_get(
-					self as *const _ as *mut _,
-					bch_sb_field_type::BCH_SB_FIELD_crypt,
-				) as *const u8;
-				if ptr.is_null() {
-					None
-				} else {
-					let offset = offset_of!(bch_sb_field_crypt, field);
-					Some(&*((ptr.sub(offset)) as *const _))
-				}
-			}
+
+	let fs = spec.resolve(&fss)?;
+
+	// Check this first, since a filesystem that is already mounted needs neither its key nor
+	// all of its devices
+	if let Some(mountpoint) = &opt.mountpoint {
+		let mounted = fs.mountpoints()?;
+		let target = mountpoint
+			.canonicalize()
+			.unwrap_or_else(|_| mountpoint.clone());
+		if mounted.contains(&target) {
+			info!(
+				"{} is already mounted on {}",
+				fs.uuid(),
+				mountpoint.display()
+			);
+			return Ok(());
 		}
-		pub fn uuid(&self) -> uuid::Uuid {
-			uuid::Uuid::from_bytes(self.user_uuid.b)
+		match mounted.first() {
+			Some(_) if !opt.bind => {
+				return Err(anyhow::Error::new(Error::Mounted {
+					uuid: *fs.uuid(),
+					mountpoints: mounted,
+				})
+				.context(format!(
+					"Not mounting it again, use --bind to mount it on {} too",
+					mountpoint.display()
+				)));
+			}
+			Some(m) if fake => {
+				info!(
+					"Not bind mounting {} on {}, fake mount requested",
+					m.display(),
+					mountpoint.display()
+				);
+				return Ok(());
+			}
+			Some(m) => {
+				info!(
+					"{} is already mounted on {}, bind mounting it",
+					fs.uuid(),
+					m.display()
+				);
+				return Ok(filesystem::bind_mount(m, mountpoint)?);
+			}
+			None => {}
 		}
+	}
 
-		/// Get the nonce used to encrypt the superblock
-		pub fn nonce(&self) -> nonce {
-			use byteorder::{ReadBytesExt, LittleEndian};
-			let mut internal_uuid = &self.uuid.b[..];
-			let dword1 = internal_uuid.read_u32::<LittleEndian>().unwrap();
-			let dword2 = internal_uuid.read_u32::<LittleEndian>().unwrap();
-			nonce { d: [0, 0, dword1, dword2] }
-		}
+	// Degraded mode can also be asked for with the mount options
+	let mut options = opt.options.clone();
+	let degraded = if options.has("very_degraded") {
+		filesystem::Degraded::VeryDegraded
+	} else if options.has("degraded") {
+		filesystem::Degraded::Degraded
+	} else {
+		filesystem::Degraded::No
+	};
+	if let Some(o) = fs.degraded_option(opt.degraded().max(degraded))? {
+		options.enable(o);
 	}
-	impl bch_sb_handle {
-		pub fn sb(&self) -> &bch_sb {
-			unsafe { &*self.sb }
+	options.check(opt.sloppy)?;
+
+	if fs.encrypted() {
+		info!("Making sure key is loaded for this filesystem");
+		let serial = key::prepare_key(
+			&fs,
+			opt.password.clone(),
+			opt.keyring,
+			opt.key_timeout.map(Duration::from_secs),
+			opt.attempts,
+		)?;
+		info!(
+			"Using the key of {} in the {} keyring ({})",
+			fs.uuid(),
+			opt.keyring,
+			serial
+		);
+	}
+
+	match &opt.mountpoint {
+		None => Ok(()),
+		Some(mountpoint) if fake => {
+			info!("Not mounting {} on {}, fake mount requested", fs.uuid(), mountpoint.display());
+			Ok(())
 		}
+		Some(mountpoint) => Ok(fs.mount(mountpoint, &options)?),
 	}
 }
 
-fn main() -> anyhow::Result<()> {
+/// Print all the bcachefs filesystems we can find
+fn list(json: bool) -> anyhow::Result<()> {
 	use itertools::Itertools;
-	use log::{info, trace};
 
-	env_logger::init();
-	let opt = Options::from_args();
-	trace!("{:?}", opt);
+	#[derive(serde::Serialize)]
+	struct Entry {
+		uuid: String,
+		label: Option<String>,
+		encrypted: bool,
+		devices: Vec<String>,
+		missing_devices: Vec<filesystem::MissingDevice>,
+		mountpoints: Vec<String>,
+	}
 
 	let fss = filesystem::probe_filesystems()?;
-	info!("Found {} bcachefs filesystems: ", fss.len());
-	for fs in fss.values() {
-		info!(
-			"{} ({}): {}",
-			fs.uuid(),
-			if fs.encrypted() {
+	let mut entries = fss
+		.values()
+		.map(|fs| {
+			Ok(Entry {
+				uuid: fs.uuid().to_string(),
+				label: fs.label().clone(),
+				encrypted: fs.encrypted(),
+				devices: fs.devices().iter().map(|d| d.display().to_string()).collect(),
+				missing_devices: fs.missing_devices(),
+				mountpoints: fs
+					.mountpoints()?
+					.iter()
+					.map(|p| p.display().to_string())
+					.collect(),
+			})
+		})
+		.collect::<anyhow::Result<Vec<_>>>()?;
+	entries.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+	if json {
+		println!("{}", serde_json::to_string_pretty(&entries)?);
+		return Ok(());
+	}
+	for e in entries {
+		println!(
+			"{} {} ({})",
+			e.uuid,
+			e.label.as_deref().unwrap_or("-"),
+			if e.encrypted {
 				"encrypted"
 			} else {
 				"unencrypted"
-			},
-			fs.devices().iter().map(|d| d.display()).join(" ")
+			}
 		);
+		println!("\tdevices: {}", e.devices.iter().join(" "));
+		if !e.missing_devices.is_empty() {
+			println!("\tmissing: {}", e.missing_devices.iter().join(", "));
+		}
+		if e.mountpoints.is_empty() {
+			println!("\tnot mounted");
+		} else {
+			println!("\tmounted on: {}", e.mountpoints.iter().join(" "));
+		}
 	}
+	Ok(())
+}
 
-	if let Some(fs) = fss.get(&opt.uuid) {
-		if fs.encrypted() {
-			info!("Making sure key is loaded for this filesystem");
-			key::prepare_key(&fs, opt.password)?;
-		}
+/// Load the key of the filesystem, so it can be mounted later
+fn unlock(
+	spec: &spec::Spec,
+	password: PasswordInput,
+	keyring: key::Keyring,
+	attempts: u32,
+) -> anyhow::Result<()> {
+	let fss = filesystem::probe_filesystems()?;
+	let fs = spec.resolve(&fss)?;
+	if !fs.encrypted() {
+		return Err(Error::NotEncrypted(*fs.uuid()).into());
+	}
+	let serial = key::prepare_key(fs, password, keyring, None, attempts)?;
+	println!(
+		"The key of {} is in the {} keyring ({})",
+		fs.uuid(),
+		keyring,
+		serial
+	);
+	Ok(())
+}
+
+/// Remove the key of the filesystem from the keyring
+fn lock(spec: &spec::Spec, keyring: key::Keyring) -> anyhow::Result<()> {
+	let fss = filesystem::probe_filesystems()?;
+	let fs = spec.resolve(&fss)?;
+	if !fs.encrypted() {
+		return Err(Error::NotEncrypted(*fs.uuid()).into());
+	}
+	lock_key(fs, keyring)
+}
+
+/// Revoke the key of the filesystem, warning if it is still mounted
+fn lock_key(fs: &filesystem::FileSystem, keyring: key::Keyring) -> anyhow::Result<()> {
+	use itertools::Itertools;
+	use log::{info, warn};
 
-		if let Some(p) = opt.mountpoint {
-			fs.mount(&p, &opt.options)
+	let mountpoints = fs.mountpoints()?;
+	if !mountpoints.is_empty() {
+		warn!(
+			"Filesystem {} is still mounted on {}",
+			fs.uuid(),
+			mountpoints.iter().map(|p| p.display()).join(" ")
+		);
+	}
+	if !key::revoke_key(fs.uuid(), keyring)? {
+		info!("No key of {} is found in the {} keyring", fs.uuid(), keyring);
+	}
+	Ok(())
+}
+
+/// Print the encryption details of the filesystem
+fn info(spec: &spec::Spec, keyring: key::Keyring, json: bool) -> anyhow::Result<()> {
+	#[derive(serde::Serialize)]
+	struct Info {
+		uuid: String,
+		label: Option<String>,
+		encrypted: bool,
+		kdf: Option<key::Kdf>,
+		/// Bytes of memory needed to derive the key
+		kdf_memory: Option<u64>,
+		/// Salsa20/8 rounds needed to derive the key
+		kdf_rounds: Option<u64>,
+		key_loaded: Option<bool>,
+	}
+
+	let fss = filesystem::probe_filesystems()?;
+	let fs = spec.resolve(&fss)?;
+	let kdf = key::Kdf::of(fs);
+	let info = Info {
+		uuid: fs.uuid().to_string(),
+		label: fs.label().clone(),
+		encrypted: fs.encrypted(),
+		kdf_memory: kdf.as_ref().and_then(|k| k.memory()),
+		kdf_rounds: kdf.as_ref().and_then(|k| k.rounds()),
+		kdf,
+		key_loaded: if fs.encrypted() {
+			Some(key::has_key(fs.uuid(), keyring)?)
 		} else {
-			Ok(())
+			None
+		},
+	};
+
+	if json {
+		println!("{}", serde_json::to_string_pretty(&info)?);
+		return Ok(());
+	}
+	println!("{} {}", info.uuid, info.label.as_deref().unwrap_or("-"));
+	if !info.encrypted {
+		println!("\tnot encrypted");
+		return Ok(());
+	}
+	println!("\tencrypted: yes");
+	if let Some(kdf) = &info.kdf {
+		println!("\tkdf: {}", kdf);
+	}
+	if let (Some(memory), Some(rounds)) = (info.kdf_memory, info.kdf_rounds) {
+		println!(
+			"\tunlock cost: {} MiB of memory, {} Salsa20/8 rounds",
+			memory >> 20,
+			rounds
+		);
+	}
+	println!(
+		"\tkey in the {} keyring: {}",
+		keyring,
+		if info.key_loaded == Some(true) { "yes" } else { "no" }
+	);
+	Ok(())
+}
+
+/// Change the passphrase the key of the filesystem is encrypted with
+fn passwd(
+	spec: &spec::Spec,
+	attempts: u32,
+	scrypt: &key::ScryptParams,
+	keyring: key::Keyring,
+) -> anyhow::Result<()> {
+	use log::info;
+
+	let fss = filesystem::probe_filesystems()?;
+	let fs = spec.resolve(&fss)?;
+	if !fs.encrypted() {
+		return Err(Error::NotEncrypted(*fs.uuid()).into());
+	}
+	// The kernel would overwrite our superblocks with its own copy
+	let mountpoints = fs.mountpoints()?;
+	if !mountpoints.is_empty() {
+		return Err(Error::Mounted {
+			uuid: *fs.uuid(),
+			mountpoints,
 		}
+		.into());
+	}
+	let missing = fs.missing_devices();
+	if !missing.is_empty() {
+		return Err(Error::MissingDevices {
+			uuid: *fs.uuid(),
+			missing,
+		}
+		.into());
+	}
+	key::change_passphrase(fs, attempts, scrypt)?;
+	// The key in the keyring is derived from the old passphrase, and can't decrypt the new
+	// superblock, so it would break the next mount
+	if key::revoke_key(fs.uuid(), keyring)? {
+		info!("Revoked the key derived from the old passphrase");
+	}
+	Ok(())
+}
+
+/// Find a mounted filesystem by its spec or a mountpoint, and where it is mounted. When given
+/// a mountpoint, only that is returned.
+fn find_mounted<'a>(
+	fss: &'a std::collections::HashMap<uuid::Uuid, filesystem::FileSystem>,
+	target: &str,
+) -> anyhow::Result<(&'a filesystem::FileSystem, Vec<std::path::PathBuf>)> {
+	if std::path::Path::new(target).is_dir() {
+		let dir = std::fs::canonicalize(target)?;
+		let fs = fss
+			.values()
+			.find(|fs| fs.mountpoints().map_or(false, |m| m.contains(&dir)))
+			.ok_or_else(|| Error::NothingMounted(dir.clone()))?;
+		Ok((fs, vec![dir]))
 	} else {
-		Err(anyhow!("Filesystem {} is not found", opt.uuid))
+		let fs = target.parse::<spec::Spec>()?.resolve(fss)?;
+		let mountpoints = fs.mountpoints()?;
+		if mountpoints.is_empty() {
+			return Err(Error::NotMounted(*fs.uuid()).into());
+		}
+		Ok((fs, mountpoints))
+	}
+}
+
+/// Change the mount options of a mounted filesystem. The options are shared by all the
+/// mountpoints, so it is enough to remount one of them.
+fn remount(target: &str, options: &mount_options::MountOptions) -> anyhow::Result<()> {
+	use log::info;
+
+	let fss = filesystem::probe_filesystems()?;
+	let (fs, mountpoints) = find_mounted(&fss, target)?;
+	let mountpoint = &mountpoints[0];
+	let mut options = options.clone();
+	options.check(false)?;
+	fs.remount(mountpoint, &options)
+		.with_context(|| format!("Failed to remount {}", mountpoint.display()))?;
+	info!("Remounted {} on {} with \"{}\"", fs.uuid(), mountpoint.display(), options);
+	Ok(())
+}
+
+/// Unmount the filesystem given by its spec or a mountpoint. When given a spec, the
+/// filesystem is unmounted from all its mountpoints.
+fn umount(
+	target: &str,
+	lazy: bool,
+	force: bool,
+	lock: bool,
+	keyring: key::Keyring,
+) -> anyhow::Result<()> {
+	use log::info;
+
+	let fss = filesystem::probe_filesystems()?;
+	let (fs, mountpoints) = find_mounted(&fss, target)?;
+
+	let mut flags = 0;
+	if lazy {
+		flags |= libc::MNT_DETACH;
+	}
+	if force {
+		flags |= libc::MNT_FORCE;
+	}
+	// Later mounts could be on top of earlier ones
+	for mountpoint in mountpoints.iter().rev() {
+		filesystem::umount(mountpoint, flags)
+			.with_context(|| format!("Failed to unmount {}", mountpoint.display()))?;
+		info!("Unmounted {} from {}", fs.uuid(), mountpoint.display());
+	}
+
+	if lock && fs.encrypted() {
+		lock_key(fs, keyring)?;
+	}
+	Ok(())
+}
+
+/// Entry point when we are invoked by mount(8) as `mount.bcachefs <spec> <dir> [-sfnv] [-o options]`
+fn helper_main() -> anyhow::Result<()> {
+	use log::trace;
+
+	let opt = HelperOptions::from_args();
+	let env = env_logger::Env::default().default_filter_or(if opt.verbose { "info" } else { "warn" });
+	env_logger::Builder::from_env(env).init();
+	trace!("{:?}", opt);
+
+	// fstab can't give command line options, so the password source is a mount option
+	let password = match opt.options.split(',').find(|o| o.starts_with("password=")) {
+		Some(o) => o["password=".len()..]
+			.parse()
+			.with_context(|| format!("Invalid mount option {}", o))?,
+		// At boot there is no terminal to ask on, ask through systemd's agents instead
+		None if std::fs::File::open("/dev/tty").is_err() => PasswordInput::Systemd,
+		None => PasswordInput::Ask,
+	};
+	let mount_opt = Options {
+		command: None,
+		password,
+		keyring: key::Keyring::User,
+		attempts: 3,
+		spec: None,
+		mountpoint: Some(opt.mountpoint),
+		options: opt
+			.options
+			.split(',')
+			.filter(|o| !is_userspace_option(o) && !o.starts_with("password="))
+			.collect::<Vec<_>>()
+			.join(",")
+			.parse()?,
+		degraded: false,
+		very_degraded: false,
+		wait_devices: None,
+		key_timeout: None,
+		bind: false,
+		sloppy: opt.sloppy,
+	};
+	mount(&opt.spec, &mount_opt, opt.fake)
+}
+
+fn run() -> anyhow::Result<()> {
+	use log::trace;
+
+	let argv0 = std::env::args_os().next().unwrap_or_default();
+	if std::path::Path::new(&argv0).file_name() == Some("mount.bcachefs".as_ref()) {
+		return helper_main();
+	}
+
+	// Show warnings by default, like the retries of a wrong passphrase
+	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+	let opt = Options::from_args();
+	trace!("{:?}", opt);
+
+	match opt.command {
+		Some(Command::List { json }) => list(json),
+		Some(Command::Unlock {
+			password,
+			keyring,
+			attempts,
+			spec,
+		}) => unlock(&spec, password, keyring, attempts),
+		Some(Command::Lock { keyring, spec }) => lock(&spec, keyring),
+		Some(Command::Info {
+			keyring,
+			json,
+			spec,
+		}) => info(&spec, keyring, json),
+		Some(Command::Umount {
+			lazy,
+			force,
+			lock,
+			keyring,
+			target,
+		}) => umount(&target, lazy, force, lock, keyring),
+		Some(Command::Remount { options, target }) => remount(&target, &options),
+		Some(Command::Passwd {
+			attempts,
+			scrypt_n,
+			scrypt_r,
+			scrypt_p,
+			keyring,
+			spec,
+		}) => {
+			let scrypt = key::ScryptParams {
+				n: scrypt_n,
+				r: scrypt_r,
+				p: scrypt_p,
+			};
+			passwd(&spec, attempts, &scrypt, keyring)
+		}
+		None => match &opt.spec {
+			Some(spec) => mount(spec, &opt, false),
+			None => structopt::clap::Error::with_description(
+				"The filesystem to mount is not specified",
+				structopt::clap::ErrorKind::MissingRequiredArgument,
+			)
+			.exit(),
+		},
+	}
+}
+
+/// Exit codes, so scripts can tell the common failures apart
+const EXIT_FAILURE: i32 = 1;
+/// The passphrase can't be verified after all the attempts
+const EXIT_WRONG_PASSPHRASE: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_MISSING_DEVICES: i32 = 4;
+const EXIT_PERMISSION_DENIED: i32 = 5;
+/// The mount options are invalid, or the kernel refused to mount
+const EXIT_MOUNT_REJECTED: i32 = 6;
+/// The filesystem has to be unmounted first
+const EXIT_MOUNTED: i32 = 7;
+
+fn exit_code(e: &anyhow::Error) -> i32 {
+	use bcachefs_mount::Error::*;
+	let e = match e.downcast_ref::<Error>() {
+		Some(e) => e,
+		None => return EXIT_FAILURE,
+	};
+	match e {
+		WrongPassphrase(_) => EXIT_WRONG_PASSPHRASE,
+		NotFound(_)
+		| AmbiguousLabel(_)
+		| NotAMember(_)
+		| DeviceNotFound(_)
+		| DifferentFilesystems(..)
+		| NotMounted(_)
+		| NothingMounted(_) => EXIT_NOT_FOUND,
+		MissingDevices { .. } | UnavailableData { .. } => EXIT_MISSING_DEVICES,
+		PermissionDenied(_) => EXIT_PERMISSION_DENIED,
+		InvalidOption { .. } | Mount { .. } => EXIT_MOUNT_REJECTED,
+		Mounted { .. } => EXIT_MOUNTED,
+		_ if e
+			.errno()
+			.map_or(false, |e| e.0 == libc::EPERM || e.0 == libc::EACCES) =>
+		{
+			EXIT_PERMISSION_DENIED
+		}
+		_ => EXIT_FAILURE,
+	}
+}
+
+fn main() {
+	if let Err(e) = run() {
+		eprintln!("Error: {:?}", e);
+		std::process::exit(exit_code(&e));
 	}
 }
@@ -0,0 +1,217 @@
+//! Mount through the new mount API (fsopen(2) and friends, since Linux 5.2), which unlike
+//! mount(2) lets the kernel explain why a mount failed. See
+//! Documentation/filesystems/mount_api.txt in the kernel source.
+
+use log::{info, warn};
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+
+const FSOPEN_CLOEXEC: libc::c_uint = 0x01;
+const FSMOUNT_CLOEXEC: libc::c_uint = 0x01;
+const FSCONFIG_SET_FLAG: libc::c_uint = 0;
+const FSCONFIG_SET_STRING: libc::c_uint = 1;
+const FSCONFIG_CMD_CREATE: libc::c_uint = 6;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x04;
+
+const MOUNT_ATTR_RDONLY: libc::c_uint = 0x01;
+const MOUNT_ATTR_NOSUID: libc::c_uint = 0x02;
+const MOUNT_ATTR_NODEV: libc::c_uint = 0x04;
+const MOUNT_ATTR_NOEXEC: libc::c_uint = 0x08;
+const MOUNT_ATTR_NOATIME: libc::c_uint = 0x10;
+const MOUNT_ATTR_STRICTATIME: libc::c_uint = 0x20;
+const MOUNT_ATTR_NODIRATIME: libc::c_uint = 0x80;
+
+/// Mount flags that apply to the superblock, and their names for fsconfig(2)
+const SB_FLAGS: &[(libc::c_ulong, &str)] = &[
+	(libc::MS_RDONLY, "ro"),
+	(libc::MS_SYNCHRONOUS, "sync"),
+	(libc::MS_DIRSYNC, "dirsync"),
+	(1 << 25, "lazytime"), // MS_LAZYTIME
+	(libc::MS_MANDLOCK, "mand"),
+];
+
+/// Mount flags that apply to the mountpoint, and their attributes for fsmount(2)
+const MOUNT_ATTRS: &[(libc::c_ulong, libc::c_uint)] = &[
+	(libc::MS_RDONLY, MOUNT_ATTR_RDONLY),
+	(libc::MS_NOSUID, MOUNT_ATTR_NOSUID),
+	(libc::MS_NODEV, MOUNT_ATTR_NODEV),
+	(libc::MS_NOEXEC, MOUNT_ATTR_NOEXEC),
+	(libc::MS_NOATIME, MOUNT_ATTR_NOATIME),
+	(libc::MS_STRICTATIME, MOUNT_ATTR_STRICTATIME),
+	(libc::MS_NODIRATIME, MOUNT_ATTR_NODIRATIME),
+];
+
+/// Why a mount failed
+#[derive(Debug)]
+pub struct Failure {
+	pub errno: errno::Errno,
+	/// Error messages from the kernel
+	pub messages: Vec<String>,
+}
+
+impl From<errno::Errno> for Failure {
+	fn from(errno: errno::Errno) -> Self {
+		Self {
+			errno,
+			messages: vec![],
+		}
+	}
+}
+
+impl From<std::ffi::NulError> for Failure {
+	fn from(_: std::ffi::NulError) -> Self {
+		errno::Errno(libc::EINVAL).into()
+	}
+}
+
+/// A filesystem context made by fsopen(2), the kernel queues its messages on it
+struct FsContext {
+	fd: RawFd,
+}
+
+impl FsContext {
+	fn open(fstype: &str) -> Result<Self, Failure> {
+		let fstype = CString::new(fstype)?;
+		let fd = unsafe { libc::syscall(libc::SYS_fsopen, fstype.as_ptr(), FSOPEN_CLOEXEC) };
+		if fd < 0 {
+			match errno::errno() {
+				// Seccomp filters that don't know about the new mount API, e.g. in containers,
+				// reject it with EPERM. If we really lack the permission, mount(2) will say so.
+				errno::Errno(libc::EPERM) => Err(errno::Errno(libc::ENOSYS).into()),
+				e => Err(e.into()),
+			}
+		} else {
+			Ok(Self { fd: fd as RawFd })
+		}
+	}
+
+	fn config(
+		&self,
+		cmd: libc::c_uint,
+		key: Option<&str>,
+		value: Option<&str>,
+	) -> Result<(), Failure> {
+		let key = key.map(CString::new).transpose()?;
+		let value = value.map(CString::new).transpose()?;
+		let ret = unsafe {
+			libc::syscall(
+				libc::SYS_fsconfig,
+				self.fd,
+				cmd,
+				key.as_ref().map_or(std::ptr::null(), |k| k.as_ptr()),
+				value.as_ref().map_or(std::ptr::null(), |v| v.as_ptr()),
+				0,
+			)
+		};
+		if ret < 0 {
+			Err(self.error())
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Make an error from errno, with the error messages from the kernel if there are any
+	fn error(&self) -> Failure {
+		let errno = errno::errno();
+		Failure {
+			errno,
+			messages: self.messages(),
+		}
+	}
+
+	/// Read the queued messages, the warnings and informational ones are logged, and the
+	/// errors are returned
+	fn messages(&self) -> Vec<String> {
+		let mut errors = vec![];
+		let mut buf = [0u8; 4096];
+		loop {
+			let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+			if n <= 0 {
+				break errors;
+			}
+			// Every message is prefixed with its level, like "e " for errors
+			let message = String::from_utf8_lossy(&buf[..n as usize]);
+			let message = message.trim_end();
+			let text = message.get(2..).unwrap_or("");
+			match message.get(..2) {
+				Some("e ") => errors.push(text.to_owned()),
+				Some("w ") => warn!("{}", text),
+				_ => info!("{}", text),
+			}
+		}
+	}
+}
+
+impl Drop for FsContext {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.fd) };
+	}
+}
+
+/// Whether the error is because the kernel doesn't have the new mount API, or we are not allowed
+/// to use it
+pub fn is_unsupported(e: &Failure) -> bool {
+	e.errno.0 == libc::ENOSYS
+}
+
+/// Mount `source` on `target`. `data` are the filesystem specific options, and `mountflags`
+/// the flags of mount(2).
+pub fn mount(
+	fstype: &str,
+	source: &str,
+	target: &std::path::Path,
+	data: Option<&str>,
+	mountflags: libc::c_ulong,
+) -> Result<(), Failure> {
+	use std::os::unix::ffi::OsStrExt;
+
+	let fs = FsContext::open(fstype)?;
+	fs.config(FSCONFIG_SET_STRING, Some("source"), Some(source))?;
+	for (flag, name) in SB_FLAGS {
+		if mountflags & flag != 0 {
+			fs.config(FSCONFIG_SET_FLAG, Some(name), None)?;
+		}
+	}
+	for option in data
+		.iter()
+		.flat_map(|d| d.split(','))
+		.filter(|o| !o.is_empty())
+	{
+		let mut kv = option.splitn(2, '=');
+		match (kv.next(), kv.next()) {
+			(Some(key), Some(value)) => fs.config(FSCONFIG_SET_STRING, Some(key), Some(value))?,
+			_ => fs.config(FSCONFIG_SET_FLAG, Some(option), None)?,
+		}
+	}
+	fs.config(FSCONFIG_CMD_CREATE, None, None)?;
+
+	let attrs = MOUNT_ATTRS
+		.iter()
+		.filter(|(flag, _)| mountflags & flag != 0)
+		.fold(0, |attrs, (_, attr)| attrs | attr);
+	let mnt = unsafe { libc::syscall(libc::SYS_fsmount, fs.fd, FSMOUNT_CLOEXEC, attrs) };
+	if mnt < 0 {
+		return Err(fs.error());
+	}
+	let mnt = mnt as RawFd;
+
+	let target = CString::new(target.as_os_str().as_bytes())?;
+	let empty = c_str!("");
+	let ret = unsafe {
+		libc::syscall(
+			libc::SYS_move_mount,
+			mnt,
+			empty,
+			libc::AT_FDCWD,
+			target.as_ptr(),
+			MOVE_MOUNT_F_EMPTY_PATH,
+		)
+	};
+	let err = errno::errno();
+	unsafe { libc::close(mnt) };
+	if ret < 0 {
+		Err(err.into())
+	} else {
+		Ok(())
+	}
+}
@@ -0,0 +1,406 @@
+use crate::bcachefs;
+
+/// The generic mount options, and their mountflags
+const FLAGS: &[(&str, libc::c_ulong)] = &[
+	("ro", libc::MS_RDONLY),
+	("sync", libc::MS_SYNCHRONOUS),
+	("dirsync", libc::MS_DIRSYNC),
+	("mand", libc::MS_MANDLOCK),
+	("lazytime", 1 << 25), // MS_LAZYTIME
+	("nosuid", libc::MS_NOSUID),
+	("nodev", libc::MS_NODEV),
+	("noexec", libc::MS_NOEXEC),
+	("noatime", libc::MS_NOATIME),
+	("nodiratime", libc::MS_NODIRATIME),
+	("relatime", libc::MS_RELATIME),
+	("strictatime", libc::MS_STRICTATIME),
+];
+
+/// The generic mount options that turn a flag off
+const CLEAR_FLAGS: &[(&str, libc::c_ulong)] = &[
+	("rw", libc::MS_RDONLY),
+	("async", libc::MS_SYNCHRONOUS),
+	("nomand", libc::MS_MANDLOCK),
+	("nolazytime", 1 << 25),
+	("suid", libc::MS_NOSUID),
+	("dev", libc::MS_NODEV),
+	("exec", libc::MS_NOEXEC),
+	("atime", libc::MS_NOATIME),
+	("diratime", libc::MS_NODIRATIME),
+	("norelatime", libc::MS_RELATIME),
+	("nostrictatime", libc::MS_STRICTATIME),
+];
+
+/// Mount options, split into the mountflags of mount(2), and the bcachefs specific options.
+/// The string form is the comma-separated list taken by mount(8).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MountOptions {
+	flags: libc::c_ulong,
+	/// Flags explicitly turned off, like with "exec"
+	cleared: libc::c_ulong,
+	/// Names and values of the bcachefs options, in the order they are given
+	options: Vec<(String, Option<String>)>,
+}
+
+macro_rules! flag_setters {
+	($($(#[$doc:meta])* $name:ident => $flag:expr,)*) => {
+		$(
+			$(#[$doc])*
+			pub fn $name(&mut self, on: bool) -> &mut Self {
+				self.set_flag($flag, on)
+			}
+		)*
+	};
+}
+
+impl MountOptions {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	flag_setters! {
+		/// Mount read-only
+		read_only => libc::MS_RDONLY,
+		/// Make writes synchronous
+		sync => libc::MS_SYNCHRONOUS,
+		/// Make directory changes synchronous
+		dirsync => libc::MS_DIRSYNC,
+		/// Allow mandatory locks
+		mand => libc::MS_MANDLOCK,
+		/// Only update times in memory
+		lazytime => 1 << 25,
+		/// Ignore set-user-ID and set-group-ID bits
+		nosuid => libc::MS_NOSUID,
+		/// Don't allow access to device files
+		nodev => libc::MS_NODEV,
+		/// Don't allow executing programs
+		noexec => libc::MS_NOEXEC,
+		/// Don't update access times
+		noatime => libc::MS_NOATIME,
+		/// Don't update access times of directories
+		nodiratime => libc::MS_NODIRATIME,
+		/// Update access times relative to the modification time
+		relatime => libc::MS_RELATIME,
+		/// Always update access times
+		strictatime => libc::MS_STRICTATIME,
+	}
+
+	/// Set a bcachefs option, replacing the value it already has
+	pub fn set(&mut self, name: impl Into<String>, value: impl ToString) -> &mut Self {
+		self.set_option(name.into(), Some(value.to_string()))
+	}
+
+	/// Turn on a boolean bcachefs option
+	pub fn enable(&mut self, name: impl Into<String>) -> &mut Self {
+		self.set_option(name.into(), None)
+	}
+
+	/// Remove a bcachefs option, so its default is used
+	pub fn remove(&mut self, name: &str) -> &mut Self {
+		self.options.retain(|(n, _)| n != name);
+		self
+	}
+
+	/// Whether the bcachefs option is given
+	pub fn has(&self, name: &str) -> bool {
+		self.options.iter().any(|(n, _)| n == name)
+	}
+
+	fn set_flag(&mut self, flag: libc::c_ulong, on: bool) -> &mut Self {
+		if on {
+			self.flags |= flag;
+			self.cleared &= !flag;
+		} else {
+			self.flags &= !flag;
+			self.cleared |= flag;
+		}
+		self
+	}
+
+	fn set_option(&mut self, name: String, value: Option<String>) -> &mut Self {
+		match self.options.iter_mut().find(|(n, _)| *n == name) {
+			Some(option) => option.1 = value,
+			None => self.options.push((name, value)),
+		}
+		self
+	}
+
+	/// The mountflags for mount(2)
+	pub fn flags(&self) -> libc::c_ulong {
+		self.flags
+	}
+
+	/// The mount flags of a mounted filesystem, from its options in mountinfo
+	pub(crate) fn from_mountinfo(options: &str, super_options: &str) -> Self {
+		let mut ret: Self = options.parse().unwrap();
+		ret.flags |= super_options.parse::<Self>().unwrap().flags;
+		ret.cleared = 0;
+		ret.options.clear();
+		ret
+	}
+
+	/// Put these options over the ones a filesystem is mounted with, as mount(8) does on
+	/// remount, since MS_REMOUNT replaces all the flags. Flags not given here are kept.
+	pub fn merged_with(&self, current: &Self) -> Self {
+		const ATIME: libc::c_ulong = libc::MS_NOATIME | libc::MS_RELATIME | libc::MS_STRICTATIME;
+		let mut kept = current.flags & !self.cleared;
+		// The kernel picks one of these, so a new one replaces the current one
+		if self.flags & ATIME != 0 {
+			kept &= !ATIME;
+		}
+		Self {
+			flags: kept | self.flags,
+			cleared: self.cleared,
+			options: self.options.clone(),
+		}
+	}
+
+	/// The bcachefs options, as the data for mount(2)
+	pub fn data(&self) -> Option<String> {
+		use itertools::Itertools;
+		if self.options.is_empty() {
+			None
+		} else {
+			Some(self.options.iter().map(FsOption).join(","))
+		}
+	}
+
+	/// Check the bcachefs options against the option table of libbcachefs, so mistakes are
+	/// reported with a better error than EINVAL from the kernel. Unknown options are an error,
+	/// unless `sloppy`, in which case they are dropped.
+	pub fn check(&mut self, sloppy: bool) -> crate::Result<()> {
+		use bcachefs::{bch_option, opt_mode, opt_type};
+		use log::warn;
+
+		let invalid = |name: &str, reason: String| crate::Error::InvalidOption {
+			option: name.to_owned(),
+			reason,
+		};
+
+		let mut unknown = vec![];
+		for (name, value) in &self.options {
+			// Boolean options can be turned off with a "no" prefix
+			let (opt, negated) = match bch_option::lookup(name) {
+				Some(opt) => (opt, false),
+				None => match name
+					.get(2..)
+					.filter(|_| name.starts_with("no"))
+					.and_then(bch_option::lookup)
+				{
+					Some(opt) if opt.type_ == opt_type::BCH_OPT_BOOL => (opt, true),
+					_ if sloppy => {
+						warn!("Ignoring unknown mount option {}", name);
+						unknown.push(name.clone());
+						continue;
+					}
+					_ => return Err(invalid(name, "is unknown".to_owned())),
+				},
+			};
+			if (opt.mode & opt_mode::OPT_MOUNT).0 == 0 {
+				return Err(invalid(name, "can't be set when mounting".to_owned()));
+			}
+			match (opt.type_, value) {
+				(opt_type::BCH_OPT_BOOL, None) => {}
+				(_, Some(_)) if negated => {
+					return Err(invalid(name, "doesn't take a value".to_owned()));
+				}
+				(_, None) => return Err(invalid(name, "needs a value".to_owned())),
+				// Parsing these needs the filesystem to be open, e.g. to find devices by label
+				(opt_type::BCH_OPT_FN, Some(_)) => {}
+				(_, Some(value)) if opt.parse(value).is_none() => {
+					let choices = opt.choices();
+					return Err(invalid(
+						name,
+						if choices.is_empty() {
+							format!("has an invalid value {}", value)
+						} else {
+							format!(
+								"has an invalid value {}, possible values are: {}",
+								value,
+								choices.join(", ")
+							)
+						},
+					));
+				}
+				(_, Some(_)) => {}
+			}
+		}
+		self.options.retain(|(n, _)| !unknown.contains(n));
+		Ok(())
+	}
+}
+
+/// Formats a bcachefs option as `name` or `name=value`
+struct FsOption<'a>(&'a (String, Option<String>));
+
+impl std::fmt::Display for FsOption<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match &self.0 {
+			(name, Some(value)) => write!(f, "{}={}", name, value),
+			(name, None) => write!(f, "{}", name),
+		}
+	}
+}
+
+impl std::str::FromStr for MountOptions {
+	type Err = std::convert::Infallible;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut ret = Self::new();
+		for option in s.split(',') {
+			match option {
+				"" | "defaults" => {}
+				_ => {
+					let set = FLAGS.iter().map(|(name, flag)| (name, flag, true));
+					let clear = CLEAR_FLAGS.iter().map(|(name, flag)| (name, flag, false));
+					match set.chain(clear).find(|(name, _, _)| **name == option) {
+						Some((_, flag, on)) => {
+							ret.set_flag(*flag, on);
+						}
+						None => {
+							let mut kv = option.splitn(2, '=');
+							let name = kv.next().unwrap().to_owned();
+							ret.set_option(name, kv.next().map(str::to_owned));
+						}
+					}
+				}
+			}
+		}
+		Ok(ret)
+	}
+}
+
+impl std::fmt::Display for MountOptions {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		use itertools::Itertools;
+		let flags = FLAGS
+			.iter()
+			.filter(|(_, flag)| self.flags & flag != 0)
+			.map(|(name, _)| name.to_string());
+		let cleared = CLEAR_FLAGS
+			.iter()
+			.filter(|(_, flag)| self.cleared & flag != 0)
+			.map(|(name, _)| name.to_string());
+		let options = self.options.iter().map(|o| FsOption(o).to_string());
+		write!(f, "{}", flags.chain(cleared).chain(options).join(","))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(s: &str) -> MountOptions {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn from_str() {
+		let opts = parse("ro,noatime,exec,metadata_replicas=2,degraded");
+		assert_eq!(opts.flags(), libc::MS_RDONLY | libc::MS_NOATIME);
+		assert_eq!(opts.data().as_deref(), Some("metadata_replicas=2,degraded"));
+
+		assert_eq!(parse("defaults"), MountOptions::new());
+		assert_eq!(parse("ro,rw").flags(), 0);
+		assert_eq!(parse("noexec,exec").flags(), 0);
+		assert_eq!(
+			parse("compression=lz4,compression=zstd").data().as_deref(),
+			Some("compression=zstd")
+		);
+	}
+
+	#[test]
+	fn display_round_trip() {
+		for s in &[
+			"",
+			"ro",
+			"ro,noatime,exec,metadata_replicas=2,degraded",
+			"nosuid,nodev,rw,suid,verbose",
+			"sync,lazytime,nostrictatime,compression=lz4",
+		] {
+			let opts = parse(s);
+			assert_eq!(parse(&opts.to_string()), opts, "{}", s);
+		}
+		assert_eq!(parse("degraded,ro").to_string(), "ro,degraded");
+	}
+
+	#[test]
+	fn setters() {
+		let mut opts = MountOptions::new();
+		opts.read_only(true)
+			.noexec(true)
+			.set("compression", "lz4")
+			.enable("degraded");
+		opts.set("compression", "zstd");
+		assert_eq!(opts.to_string(), "ro,noexec,compression=zstd,degraded");
+		assert!(opts.has("degraded"));
+		opts.remove("degraded").noexec(false);
+		assert_eq!(opts.to_string(), "ro,exec,compression=zstd");
+	}
+
+	#[test]
+	fn merged_with() {
+		let current =
+			MountOptions::from_mountinfo("rw,nosuid,nodev,noexec,noatime", "rw,lazytime,degraded");
+		assert_eq!(current.to_string(), "lazytime,nosuid,nodev,noexec,noatime");
+
+		let merged = parse("ro").merged_with(&current);
+		assert_eq!(merged.flags(), current.flags() | libc::MS_RDONLY);
+
+		let merged = parse("exec,relatime,verbose").merged_with(&current);
+		assert_eq!(
+			merged.flags(),
+			(1 << 25) | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RELATIME
+		);
+		assert_eq!(merged.data().as_deref(), Some("verbose"));
+	}
+
+	fn rejected(s: &str) -> (String, String) {
+		match parse(s).check(false) {
+			Err(crate::Error::InvalidOption { option, reason }) => (option, reason),
+			r => panic!("{}: {:?}", s, r),
+		}
+	}
+
+	#[test]
+	fn check() {
+		let mut opts = parse("ro,degraded,metadata_replicas=2,str_hash=siphash");
+		opts.check(false).unwrap();
+		assert_eq!(
+			opts.to_string(),
+			"ro,degraded,metadata_replicas=2,str_hash=siphash"
+		);
+		parse("nodegraded").check(false).unwrap();
+
+		let reason = |s| rejected(s).1;
+		assert_eq!(
+			rejected("bogus"),
+			("bogus".to_owned(), "is unknown".to_owned())
+		);
+		// Only boolean options can be negated
+		assert_eq!(reason("nometadata_replicas"), "is unknown");
+		assert_eq!(reason("nodegraded=1"), "doesn't take a value");
+		assert_eq!(reason("metadata_replicas"), "needs a value");
+		assert_eq!(reason("block_size=8"), "can't be set when mounting");
+		assert_eq!(reason("metadata_replicas=0"), "has an invalid value 0");
+		assert_eq!(
+			reason("str_hash=md5"),
+			"has an invalid value md5, possible values are: crc32c, crc64, siphash"
+		);
+	}
+
+	#[test]
+	fn check_sloppy() {
+		let mut opts = parse("ro,bogus,degraded,nobogus=1");
+		opts.check(true).unwrap();
+		assert_eq!(opts.to_string(), "ro,degraded");
+		// Only unknown options are ignored
+		assert_eq!(
+			parse("bogus,metadata_replicas")
+				.check(true)
+				.unwrap_err()
+				.to_string(),
+			"Mount option metadata_replicas needs a value"
+		);
+	}
+}
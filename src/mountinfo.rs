@@ -0,0 +1,104 @@
+use std::path::PathBuf;
+
+/// One entry in /proc/self/mountinfo
+#[derive(Debug)]
+pub struct MountInfo {
+	/// Where it is mounted
+	pub mountpoint: PathBuf,
+	/// Filesystem type
+	pub fstype: String,
+	/// Filesystem specific source, for bcachefs this is the ":" separated list of devices
+	pub source: String,
+	/// Options of the mountpoint, like "rw,nosuid"
+	pub options: String,
+	/// Options of the superblock, which include the filesystem specific ones
+	pub super_options: String,
+}
+
+/// Undo the octal escaping of whitespaces and backslashes done by the kernel
+fn unescape(s: &str) -> String {
+	let mut ret = String::with_capacity(s.len());
+	let mut rest = s;
+	while let Some(i) = rest.find('\\') {
+		ret.push_str(&rest[..i]);
+		let code = rest.get(i + 1..i + 4).and_then(|c| u8::from_str_radix(c, 8).ok());
+		match code {
+			Some(c) => {
+				ret.push(c as char);
+				rest = &rest[i + 4..];
+			}
+			None => {
+				ret.push('\\');
+				rest = &rest[i + 1..];
+			}
+		}
+	}
+	ret.push_str(rest);
+	ret
+}
+
+/// Parse one line of mountinfo, the format is described in proc(5):
+///
+/// `36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue`
+fn parse_line(line: &str) -> Option<MountInfo> {
+	let mut fields = line.split(' ');
+	let mountpoint = fields.nth(4)?;
+	let options = fields.next()?;
+	// Skip the optional fields
+	let mut fields = fields.skip_while(|f| *f != "-").skip(1);
+	Some(MountInfo {
+		mountpoint: unescape(mountpoint).into(),
+		options: unescape(options),
+		fstype: unescape(fields.next()?),
+		source: unescape(fields.next()?),
+		super_options: unescape(fields.next()?),
+	})
+}
+
+/// Get all the mounts visible to this process
+pub fn mounts() -> crate::Result<Vec<MountInfo>> {
+	const MOUNTINFO: &str = "/proc/self/mountinfo";
+	Ok(std::fs::read_to_string(MOUNTINFO)
+		.map_err(|e| crate::Error::Io {
+			path: MOUNTINFO.into(),
+			source: e,
+		})?
+		.lines()
+		.filter_map(parse_line)
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unescape_octal() {
+		assert_eq!(unescape("/mnt/my\\040data"), "/mnt/my data");
+		assert_eq!(unescape("a\\011b\\012c\\134d"), "a\tb\nc\\d");
+		assert_eq!(unescape("no escapes"), "no escapes");
+		// Not a valid escape, kept as it is
+		assert_eq!(unescape("a\\0"), "a\\0");
+		assert_eq!(unescape("a\\xyz"), "a\\xyz");
+	}
+
+	#[test]
+	fn parse_line_fields() {
+		let m = parse_line(
+			"36 35 98:0 / /mnt/my\\040data rw,nosuid,noatime shared:1 master:2 - bcachefs /dev/sda:/dev/sdb rw,degraded",
+		)
+		.unwrap();
+		assert_eq!(m.mountpoint, PathBuf::from("/mnt/my data"));
+		assert_eq!(m.options, "rw,nosuid,noatime");
+		assert_eq!(m.fstype, "bcachefs");
+		assert_eq!(m.source, "/dev/sda:/dev/sdb");
+		assert_eq!(m.super_options, "rw,degraded");
+
+		let m = parse_line("21 1 0:19 / /proc rw,relatime - proc proc rw").unwrap();
+		assert_eq!(m.mountpoint, PathBuf::from("/proc"));
+		assert_eq!(m.source, "proc");
+
+		assert!(parse_line("21 1 0:19 / /proc").is_none());
+		assert!(parse_line("").is_none());
+	}
+}
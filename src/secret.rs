@@ -0,0 +1,181 @@
+//! Holders for key material, so it doesn't linger in freed memory, swap or core dumps.
+
+use crate::Error;
+use std::alloc::Layout;
+use std::os::unix::io::RawFd;
+use std::ptr::NonNull;
+
+/// A value kept in its own pages, which are locked into memory, excluded from core dumps, and
+/// zeroed when dropped. `T` must not own any memory outside of itself.
+pub struct Secret<T: Copy> {
+	ptr: NonNull<T>,
+	layout: Layout,
+}
+
+impl<T: Copy> Secret<T> {
+	pub fn new(value: T) -> Self {
+		let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+		let size = (std::mem::size_of::<T>().max(1) + page - 1) / page * page;
+		let layout = Layout::from_size_align(size, page.max(std::mem::align_of::<T>())).unwrap();
+		let ptr = unsafe { std::alloc::alloc_zeroed(layout) } as *mut T;
+		let ptr = NonNull::new(ptr).unwrap_or_else(|| std::alloc::handle_alloc_error(layout));
+		unsafe {
+			// Both are best effort, mlock could fail because of RLIMIT_MEMLOCK, and we don't
+			// want to refuse to work because of that
+			if libc::mlock(ptr.as_ptr() as *const _, size) != 0 {
+				log::debug!("Failed to lock memory: {}", errno::errno());
+			}
+			libc::madvise(ptr.as_ptr() as *mut _, size, libc::MADV_DONTDUMP);
+			ptr.as_ptr().write(value);
+		}
+		Self { ptr, layout }
+	}
+}
+
+impl<T: Copy> std::ops::Deref for Secret<T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		unsafe { self.ptr.as_ref() }
+	}
+}
+
+impl<T: Copy> std::ops::DerefMut for Secret<T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { self.ptr.as_mut() }
+	}
+}
+
+impl<T: Copy> Drop for Secret<T> {
+	fn drop(&mut self) {
+		let ptr = self.ptr.as_ptr() as *mut u8;
+		unsafe {
+			// Volatile writes, so they won't be optimized away
+			for i in 0..self.layout.size() {
+				std::ptr::write_volatile(ptr.add(i), 0);
+			}
+			std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+			libc::munlock(ptr as *const _, self.layout.size());
+			std::alloc::dealloc(ptr, self.layout);
+		}
+	}
+}
+
+/// Longest passphrase we accept
+const MAX_PASSPHRASE: usize = 1024;
+
+/// A passphrase in a `Secret`, always NUL terminated so it can be passed to C
+pub struct Passphrase {
+	buf: Secret<[u8; MAX_PASSPHRASE + 1]>,
+	len: usize,
+}
+
+impl Passphrase {
+	fn new() -> Self {
+		Self {
+			buf: Secret::new([0; MAX_PASSPHRASE + 1]),
+			len: 0,
+		}
+	}
+
+	fn push(&mut self, c: u8) -> crate::Result<()> {
+		if self.len >= MAX_PASSPHRASE {
+			return Err(Error::Passphrase(format!(
+				"Passphrase is longer than {} bytes",
+				MAX_PASSPHRASE
+			)));
+		}
+		self.buf[self.len] = c;
+		self.len += 1;
+		Ok(())
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> crate::Result<Self> {
+		let mut ret = Self::new();
+		for &c in bytes {
+			ret.push(c)?;
+		}
+		Ok(ret)
+	}
+
+	/// Read from `fd` one byte at a time until `end` or EOF, so no copy is left in any buffer
+	fn read_until(fd: RawFd, end: Option<u8>) -> crate::Result<Self> {
+		let mut ret = Self::new();
+		loop {
+			let mut c = 0u8;
+			let n = unsafe { libc::read(fd, &mut c as *mut u8 as *mut _, 1) };
+			if n < 0 {
+				if errno::errno().0 == libc::EINTR {
+					continue;
+				}
+				return Err(Error::sys("read"));
+			}
+			if n == 0 || Some(c) == end {
+				break;
+			}
+			ret.push(c)?;
+		}
+		Ok(ret)
+	}
+
+	/// Read the first line from `fd`
+	pub fn read_line(fd: RawFd) -> crate::Result<Self> {
+		Self::read_until(fd, Some(b'\n'))
+	}
+
+	/// Read everything from `fd`
+	pub fn read_all(fd: RawFd) -> crate::Result<Self> {
+		Self::read_until(fd, None)
+	}
+
+	/// Prompt for the passphrase on the controlling terminal, with echo turned off
+	pub fn read_from_tty(prompt: &str) -> crate::Result<Self> {
+		use std::io::Write;
+		use std::os::unix::io::AsRawFd;
+
+		const TTY: &str = "/dev/tty";
+		let io_error = |e| Error::Io {
+			path: TTY.into(),
+			source: e,
+		};
+		let mut tty = std::fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open(TTY)
+			.map_err(io_error)?;
+		let fd = tty.as_raw_fd();
+		let mut term = std::mem::MaybeUninit::<libc::termios>::uninit();
+		if unsafe { libc::tcgetattr(fd, term.as_mut_ptr()) } != 0 {
+			return Err(Error::sys("tcgetattr"));
+		}
+		let orig = unsafe { term.assume_init() };
+		let mut term = orig;
+		term.c_lflag &= !libc::ECHO;
+		term.c_lflag |= libc::ECHONL;
+
+		tty.write_all(prompt.as_bytes())
+			.and_then(|_| tty.flush())
+			.map_err(io_error)?;
+		if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) } != 0 {
+			return Err(Error::sys("tcsetattr"));
+		}
+		let ret = Self::read_line(fd);
+		unsafe { libc::tcsetattr(fd, libc::TCSANOW, &orig) };
+		ret
+	}
+
+	/// Remove trailing whitespaces
+	pub fn trim_end(&mut self) {
+		while self.len > 0 && self.buf[self.len - 1].is_ascii_whitespace() {
+			self.len -= 1;
+			self.buf[self.len] = 0;
+		}
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.buf[..self.len]
+	}
+
+	pub fn as_ptr(&self) -> *const std::os::raw::c_char {
+		self.buf.as_ptr() as *const _
+	}
+}
@@ -0,0 +1,143 @@
+use crate::filesystem::FileSystem;
+use crate::Error;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Describes which filesystem the user wants, in any of the forms accepted by mount(8)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Spec {
+	/// `<uuid>` or `UUID=<uuid>`, the external UUID of the filesystem
+	Uuid(Uuid),
+	/// `LABEL=<label>`, the label stored in the superblock
+	Label(String),
+	/// `/dev/sda` or `/dev/sda:/dev/sdb`, one or more member devices
+	Devices(Vec<PathBuf>),
+}
+
+impl std::str::FromStr for Spec {
+	type Err = Error;
+	fn from_str(s: &str) -> crate::Result<Self> {
+		let invalid = |reason: String| Error::InvalidSpec {
+			spec: s.to_owned(),
+			reason,
+		};
+		if s.starts_with("UUID=") {
+			let uuid = &s["UUID=".len()..];
+			Ok(Spec::Uuid(
+				uuid.parse()
+					.map_err(|e| invalid(format!("invalid UUID, {}", e)))?,
+			))
+		} else if s.starts_with("LABEL=") {
+			let label = &s["LABEL=".len()..];
+			if label.is_empty() {
+				Err(invalid("empty label".to_owned()))
+			} else {
+				Ok(Spec::Label(label.to_owned()))
+			}
+		} else if let Ok(uuid) = s.parse() {
+			Ok(Spec::Uuid(uuid))
+		} else if s.split(':').all(|d| !d.is_empty()) {
+			Ok(Spec::Devices(s.split(':').map(PathBuf::from).collect()))
+		} else {
+			Err(invalid("expected a UUID, a label or devices".to_owned()))
+		}
+	}
+}
+
+impl std::fmt::Display for Spec {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		use itertools::Itertools;
+		match self {
+			Spec::Uuid(uuid) => write!(f, "UUID={}", uuid),
+			Spec::Label(label) => write!(f, "LABEL={}", label),
+			Spec::Devices(devs) => write!(f, "{}", devs.iter().map(|d| d.display()).join(":")),
+		}
+	}
+}
+
+impl Spec {
+	/// Find the filesystem this spec refers to among the probed filesystems
+	pub fn resolve<'a>(&self, fss: &'a HashMap<Uuid, FileSystem>) -> crate::Result<&'a FileSystem> {
+		match self {
+			Spec::Uuid(uuid) => fss.get(uuid).ok_or_else(|| Error::NotFound(self.clone())),
+			Spec::Label(label) => match crate::filesystem::find_by_label(fss, label)[..] {
+				[fs] => Ok(fs),
+				[] => Err(Error::NotFound(self.clone())),
+				_ => Err(Error::AmbiguousLabel(label.clone())),
+			},
+			Spec::Devices(devs) => {
+				let mut found: Option<&FileSystem> = None;
+				for dev in devs {
+					let path = dev.canonicalize().map_err(|e| match e.kind() {
+						std::io::ErrorKind::NotFound => Error::DeviceNotFound(dev.clone()),
+						_ => Error::Io {
+							path: dev.clone(),
+							source: e,
+						},
+					})?;
+					let fs = fss
+						.values()
+						.find(|fs| {
+							fs.devices()
+								.iter()
+								.any(|d| d.canonicalize().map_or(false, |d| d == path))
+						})
+						.ok_or_else(|| Error::NotAMember(dev.clone()))?;
+					match found {
+						Some(f) if f.uuid() != fs.uuid() => {
+							return Err(Error::DifferentFilesystems(*f.uuid(), *fs.uuid()))
+						}
+						_ => found = Some(fs),
+					}
+				}
+				found.ok_or_else(|| Error::NotFound(self.clone()))
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const UUID: &str = "8c4a5e3c-1b0f-4c2e-9a3d-6f7e8d9c0b1a";
+
+	#[test]
+	fn from_str() {
+		let uuid: Uuid = UUID.parse().unwrap();
+		assert_eq!(UUID.parse::<Spec>().unwrap(), Spec::Uuid(uuid));
+		assert_eq!(
+			format!("UUID={}", UUID).parse::<Spec>().unwrap(),
+			Spec::Uuid(uuid)
+		);
+		assert_eq!(
+			"LABEL=data".parse::<Spec>().unwrap(),
+			Spec::Label("data".to_owned())
+		);
+		assert_eq!(
+			"/dev/sda:/dev/sdb".parse::<Spec>().unwrap(),
+			Spec::Devices(vec!["/dev/sda".into(), "/dev/sdb".into()])
+		);
+
+		for s in &["", "LABEL=", "UUID=nope", "/dev/sda::/dev/sdb", ":/dev/sda"] {
+			match s.parse::<Spec>() {
+				Err(Error::InvalidSpec { spec, .. }) => assert_eq!(spec, *s),
+				ret => panic!("{:?} parsed as {:?}", s, ret),
+			}
+		}
+	}
+
+	#[test]
+	fn display_round_trip() {
+		for s in &[
+			format!("UUID={}", UUID),
+			"LABEL=my data".to_owned(),
+			"/dev/sda:/dev/sdb".to_owned(),
+		] {
+			let spec = s.parse::<Spec>().unwrap();
+			assert_eq!(spec.to_string(), *s);
+			assert_eq!(spec.to_string().parse::<Spec>().unwrap(), spec);
+		}
+	}
+}